@@ -0,0 +1,390 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::error::Error;
+use crate::deltas;
+use crate::inventory;
+use crate::symbols;
+use chrono::{TimeZone, Utc};
+
+const QTY_TOLERANCE: f64 = 0.000000001;
+/// Relative slack when checking basis, which lot splits round.
+const BASIS_TOLERANCE: f64 = 0.000000001;
+
+
+/// One wallet's balance of one asset, as read from `wallet_balances.json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WalletBalance {
+    pub host: deltas::Host,
+    pub account: String,
+    pub asset: String,
+    pub qty: f64,
+}
+
+impl WalletBalance {
+    pub fn load_all(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Vec<Self> = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+}
+
+/// A user-specified assignment of (part of) a pooled lot to a wallet,
+/// matched on the lot's `identifier` and `timestamp`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LotAssignment {
+    pub ticker: String,
+    pub identifier: Option<String>,
+    pub timestamp: u64,
+    pub qty: f64,
+    pub host: deltas::Host,
+    pub account: String,
+}
+
+impl LotAssignment {
+    pub fn load_all(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Vec<Self> = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+}
+
+/// How pooled lots are spread over wallets at the move to per-wallet basis.
+#[derive(Clone, Debug)]
+pub enum AllocationRule {
+    /// For each ticker, wallets are filled largest balance first, each with
+    /// the oldest lots still unassigned.
+    OldestToLargest,
+    /// The listed assignments first, then `OldestToLargest` for the rest.
+    Mapping(Vec<LotAssignment>),
+}
+
+impl AllocationRule {
+    pub fn describe(&self) -> String {
+        match self {
+            AllocationRule::OldestToLargest => "for each asset, the oldest lots are assigned to the wallet with the largest balance until its balance is covered, then to the next largest".to_string(),
+            AllocationRule::Mapping(assignments) => format!("{} lots assigned to wallets as specified, the remaining lots for each asset assigned oldest first to the wallet with the largest uncovered balance", assignments.len()),
+        }
+    }
+}
+
+/// A lot (or part of one) and the wallet it was allocated to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AllocatedLot {
+    pub ticker: String,
+    pub host: deltas::Host,
+    pub account: String,
+    pub lot: inventory::Lot,
+}
+
+/// The record of a one-time allocation of pooled basis to wallets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Allocation {
+    /// Unix millis the allocation takes effect at (Jan 1 of the first
+    /// per-wallet year).
+    pub effective: u64,
+    pub rule: String,
+    pub allocated: Vec<AllocatedLot>,
+    /// Wallet balances left without lots to cover them.
+    pub uncovered: Vec<WalletBalance>,
+    /// Lots no wallet holds, spam and posted collateral, kept under their
+    /// own keys.
+    #[serde(default)]
+    pub held_apart: HashMap<String, Vec<inventory::Lot>>,
+}
+
+impl Allocation {
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Self = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
+    pub fn save (&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let json_string = serde_json::to_string_pretty(&self)?;
+        std::fs::write(path, &json_string)?;
+        Ok(())
+    }
+
+    /// Spreads the lots of `pooled` over the wallets in `balances`
+    /// following `rule`. Every lot has to land in a wallet, except those held
+    /// apart, which keep their keys: an assignment to a wallet without the
+    /// balance for it, or lots of a ticker no wallet holds, is an error.
+    pub fn allocate(pooled: &inventory::Inventory, balances: &[WalletBalance], rule: &AllocationRule, effective: u64) -> Result<Self, Box<dyn Error>> {
+        // Remaining balance to cover per ticker, per wallet.
+        let mut capacity: HashMap<String, Vec<(deltas::Host, String, f64)>> = HashMap::new();
+        for balance in balances {
+            if balance.qty <= QTY_TOLERANCE {
+                continue
+            }
+            let ticker = symbols::tax_ticker(&balance.asset, &balance.host);
+            let wallets = capacity.entry(ticker).or_default();
+            match wallets.iter_mut().find(|(h, a, _)| *h == balance.host && *a == balance.account) {
+                Some(wallet) => wallet.2 += balance.qty,
+                None => wallets.push((balance.host.clone(), balance.account.clone(), balance.qty)),
+            }
+        }
+        for wallets in capacity.values_mut() {
+            wallets.sort_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+        }
+
+        let mut remaining = pooled.clone();
+        let held_apart = pooled.0.iter()
+            .filter(|(key, _)| inventory::is_held_apart(key))
+            .map(|(key, lots)| (key.clone(), lots.clone()))
+            .collect();
+        remaining.0.retain(|key, _| !inventory::is_held_apart(key));
+        let mut allocated = Vec::new();
+
+        if let AllocationRule::Mapping(assignments) = rule {
+            for assignment in assignments {
+                let lots = remaining.0.get_mut(&assignment.ticker)
+                    .ok_or_else(|| format!("assignment for {}: no lots held", assignment.ticker))?;
+                let index = lots.iter()
+                    .position(|lot| lot.identifier == assignment.identifier && lot.timestamp == assignment.timestamp)
+                    .ok_or_else(|| format!("assignment for {}: no lot {:?} at {}", assignment.ticker, assignment.identifier, assignment.timestamp))?;
+                if assignment.qty > lots[index].qty + QTY_TOLERANCE {
+                    return Err(format!("assignment for {}: wants {} from lot {:?} which only has {}", assignment.ticker, assignment.qty, assignment.identifier, lots[index].qty).into());
+                }
+                let wallet = capacity.get_mut(&assignment.ticker)
+                    .and_then(|wallets| wallets.iter_mut().find(|(h, a, _)| *h == assignment.host && *a == assignment.account))
+                    .ok_or_else(|| format!("assignment for {}: wallet {:?}:{} holds none", assignment.ticker, assignment.host, assignment.account))?;
+                if assignment.qty > wallet.2 + QTY_TOLERANCE {
+                    return Err(format!("assignment for {}: wants {} in wallet {:?}:{} which only has {} left to cover", assignment.ticker, assignment.qty, assignment.host, assignment.account, wallet.2).into());
+                }
+
+                let lot = if assignment.qty >= lots[index].qty {
+                    lots.remove(index)
+                } else {
+                    lots[index].remove_qty(assignment.qty, &assignment.ticker)?
+                };
+                wallet.2 -= lot.qty;
+                allocated.push(AllocatedLot {
+                    ticker: assignment.ticker.clone(),
+                    host: assignment.host.clone(),
+                    account: assignment.account.clone(),
+                    lot,
+                });
+            }
+        }
+
+        let mut tickers: Vec<&String> = remaining.0.keys().collect();
+        tickers.sort();
+
+        for ticker in tickers {
+            let mut lots = remaining.0[ticker].clone();
+            lots.sort_by_key(|lot| lot.timestamp);
+
+            let wallets = match capacity.get_mut(ticker) {
+                Some(wallets) if !wallets.is_empty() => wallets,
+                _ if lots.iter().all(|lot| lot.qty <= QTY_TOLERANCE) => continue,
+                _ => return Err(format!("no wallet holds {} for its {} lot(s) to go to; assign them in a mapping", ticker, lots.len()).into()),
+            };
+
+            let mut w = 0;
+            for mut lot in lots {
+                while lot.qty > QTY_TOLERANCE {
+                    while w < wallets.len() - 1 && wallets[w].2 <= QTY_TOLERANCE {
+                        w += 1;
+                    }
+                    // Anything beyond every wallet's balance is dust the
+                    // opening reconciliation let through; it goes to the
+                    // last wallet rather than losing its basis.
+                    let piece = if lot.qty <= wallets[w].2 || w == wallets.len() - 1 {
+                        let whole = lot.clone();
+                        lot.qty = 0.0;
+                        whole
+                    } else {
+                        lot.remove_qty(wallets[w].2, ticker)?
+                    };
+                    wallets[w].2 -= piece.qty;
+                    allocated.push(AllocatedLot {
+                        ticker: ticker.clone(),
+                        host: wallets[w].0.clone(),
+                        account: wallets[w].1.clone(),
+                        lot: piece,
+                    });
+                }
+            }
+        }
+
+        let mut uncovered = Vec::new();
+        for (ticker, wallets) in &capacity {
+            for (host, account, qty) in wallets {
+                if *qty > QTY_TOLERANCE {
+                    uncovered.push(WalletBalance {
+                        host: host.clone(),
+                        account: account.clone(),
+                        asset: ticker.clone(),
+                        qty: *qty,
+                    });
+                }
+            }
+        }
+
+        Ok(Self {
+            effective,
+            rule: rule.describe(),
+            allocated,
+            uncovered,
+            held_apart,
+        })
+    }
+
+    /// The per-wallet inventory the allocation produces.
+    pub fn inventory(&self) -> inventory::Inventory {
+        let mut lots = self.held_apart.clone();
+        for a in &self.allocated {
+            lots.entry(inventory::wallet_key(&a.ticker, &a.host, &a.account)).or_default().push(a.lot.clone());
+        }
+        for l in lots.values_mut() {
+            l.sort_by_key(|lot| lot.timestamp);
+        }
+        inventory::Inventory ( lots )
+    }
+
+    /// Errors unless the allocation covers exactly the lots of `pooled`,
+    /// quantity and basis, i.e. it was made from this inventory.
+    pub fn check_against(&self, pooled: &inventory::Inventory) -> Result<(), Box<dyn Error>> {
+        let mut expected: HashMap<String, (f64, f64)> = HashMap::new();
+        for (ticker, lots) in &pooled.0 {
+            let held = expected.entry(ticker.clone()).or_default();
+            for lot in lots.iter().filter(|lot| lot.qty > QTY_TOLERANCE) {
+                held.0 += lot.qty;
+                held.1 += lot.cost;
+            }
+        }
+        let mut actual: HashMap<String, (f64, f64)> = HashMap::new();
+        let held_apart = self.held_apart.iter().flat_map(|(key, lots)| lots.iter().map(move |lot| (key, lot)));
+        for (ticker, lot) in self.allocated.iter().map(|a| (&a.ticker, &a.lot)).chain(held_apart) {
+            let held = actual.entry(ticker.clone()).or_default();
+            held.0 += lot.qty;
+            held.1 += lot.cost;
+        }
+
+        for (ticker, (qty, cost)) in &expected {
+            let (got_qty, got_cost) = actual.get(ticker).copied().unwrap_or((0.0, 0.0));
+            if (got_qty - qty).abs() > QTY_TOLERANCE {
+                return Err(format!("allocation covers {} {} but the opening inventory holds {}", got_qty, ticker, qty).into());
+            }
+            if (got_cost - cost).abs() > BASIS_TOLERANCE * cost.abs().max(1.0) {
+                return Err(format!("allocation carries {} basis for {} but the opening inventory has {}", got_cost, ticker, cost).into());
+            }
+        }
+        for ticker in actual.keys() {
+            if !expected.contains_key(ticker) {
+                return Err(format!("allocation assigns {} which the opening inventory doesn't hold", ticker).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The allocation as a CSV to attach to the return.
+    pub fn to_csv(&self) -> String {
+        let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        let mut csv = format!("# allocation of pooled cost basis to wallets effective {}\n", date(self.effective));
+        csv += &format!("# rule: {}\n", self.rule);
+        csv += "asset,host,account,quantity,acquisition_date,acquisition_identifier,cost_basis\n";
+        for a in &self.allocated {
+            csv += &format!(
+                "{},{:?},{},{:.8},{},{},{:.8}\n",
+                a.ticker,
+                a.host,
+                a.account,
+                a.lot.qty,
+                date(a.lot.timestamp),
+                a.lot.identifier.clone().unwrap_or_default(),
+                a.lot.cost,
+            );
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn balance(account: &str, qty: f64) -> WalletBalance {
+        WalletBalance { host: deltas::Host::Mainnet, account: account.to_string(), asset: "ETH".to_string(), qty }
+    }
+
+    fn pooled() -> inventory::Inventory {
+        inventory::Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 1.0, 100.0), fixtures::lot(2, 2.0, 400.0)])]) )
+    }
+
+    fn wallet_lots(allocation: &Allocation, account: &str) -> Vec<(u64, f64, f64)> {
+        allocation.allocated.iter()
+            .filter(|a| a.account == account)
+            .map(|a| (a.lot.timestamp, a.lot.qty, a.lot.cost))
+            .collect()
+    }
+
+    #[test]
+    fn oldest_lots_fill_largest_wallet_first() {
+        let balances = [balance("0xsmall", 1.0), balance("0xlarge", 2.0)];
+        let allocation = Allocation::allocate(&pooled(), &balances, &AllocationRule::OldestToLargest, 0).unwrap();
+        assert_eq!(wallet_lots(&allocation, "0xlarge"), vec![(1, 1.0, 100.0), (2, 1.0, 200.0)]);
+        assert_eq!(wallet_lots(&allocation, "0xsmall"), vec![(2, 1.0, 200.0)]);
+        assert!(allocation.uncovered.is_empty());
+        allocation.check_against(&pooled()).unwrap();
+    }
+
+    #[test]
+    fn mapping_goes_first_then_the_rest() {
+        let balances = [balance("0xsmall", 1.0), balance("0xlarge", 2.0)];
+        let mapping = AllocationRule::Mapping(vec![LotAssignment {
+            ticker: "ETH".to_string(),
+            identifier: Some("0x2".to_string()),
+            timestamp: 2,
+            qty: 1.0,
+            host: deltas::Host::Mainnet,
+            account: "0xsmall".to_string(),
+        }]);
+        let allocation = Allocation::allocate(&pooled(), &balances, &mapping, 0).unwrap();
+        assert_eq!(wallet_lots(&allocation, "0xsmall"), vec![(2, 1.0, 200.0)]);
+        assert_eq!(wallet_lots(&allocation, "0xlarge"), vec![(1, 1.0, 100.0), (2, 1.0, 200.0)]);
+        allocation.check_against(&pooled()).unwrap();
+    }
+
+    #[test]
+    fn mapping_needs_the_wallet_and_its_balance() {
+        let balances = [balance("0xsmall", 1.0), balance("0xlarge", 2.0)];
+        let assign = |account: &str, qty: f64| AllocationRule::Mapping(vec![LotAssignment {
+            ticker: "ETH".to_string(),
+            identifier: Some("0x2".to_string()),
+            timestamp: 2,
+            qty,
+            host: deltas::Host::Mainnet,
+            account: account.to_string(),
+        }]);
+        assert!(Allocation::allocate(&pooled(), &balances, &assign("0xother", 1.0), 0).is_err());
+        assert!(Allocation::allocate(&pooled(), &balances, &assign("0xsmall", 2.0), 0).is_err());
+    }
+
+    #[test]
+    fn lots_no_wallet_holds_are_an_error() {
+        assert!(Allocation::allocate(&pooled(), &[], &AllocationRule::OldestToLargest, 0).is_err());
+    }
+
+    #[test]
+    fn check_compares_basis() {
+        let balances = [balance("0xlarge", 3.0)];
+        let mut allocation = Allocation::allocate(&pooled(), &balances, &AllocationRule::OldestToLargest, 0).unwrap();
+        allocation.allocated[0].lot.cost += 1.0;
+        assert!(allocation.check_against(&pooled()).is_err());
+    }
+
+    #[test]
+    fn lots_held_apart_keep_their_keys() {
+        let mut with_collateral = pooled();
+        with_collateral.0.insert("COLLATERAL:mainnet:ETH".to_string(), vec![fixtures::lot(3, 1.0, 300.0)]);
+        let allocation = Allocation::allocate(&with_collateral, &[balance("0xlarge", 3.0)], &AllocationRule::OldestToLargest, 0).unwrap();
+        allocation.check_against(&with_collateral).unwrap();
+        let inventory = allocation.inventory();
+        assert_eq!(inventory.0["COLLATERAL:mainnet:ETH"].len(), 1);
+        assert_eq!(inventory.0.len(), 2);
+    }
+}
@@ -46,7 +46,6 @@ pub fn coingecko_id(ticker: &str) -> Option<&'static str> {
         "ARB" => Some("arbitrum"),
         "USDT" => Some("tether"),
         "STG" => Some("stargate-finance"),
-        "STGLP" => Some("stargate-finance"),
         "AERO" => Some("aerodrome-finance"),
         "DEGEN" => Some("degen-base"),
         "BRETT" => Some("based-brett"),
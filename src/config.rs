@@ -0,0 +1,181 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::error::Error;
+use crate::deltas;
+use crate::inventory;
+
+
+/// Everything that differs between one tax year and the next. The pipeline
+/// in `pipeline.rs` is the same for every year; a new year is a new
+/// `config/{year}.json` rather than a new module.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct YearConfig {
+    pub year: i32,
+    pub opening: Opening,
+    /// File in the year's data dir holding on-chain balances at Jan 1.
+    pub opening_balances: String,
+    /// File in the year's data dir holding on-chain balances at Dec 31.
+    /// Years without one skip the end-of-year reconciliation.
+    #[serde(default)]
+    pub closing_balances: Option<String>,
+    /// Assets that first appear this year and need an empty lot vector
+    /// before the deltas are applied.
+    #[serde(default)]
+    pub added_assets: Vec<String>,
+    /// Tax ticker -> on-chain balance names summed to get its opening balance.
+    /// Tickers not listed are looked up under their own name.
+    #[serde(default)]
+    pub opening_aliases: HashMap<String, Vec<String>>,
+    /// Same as `opening_aliases`, for the closing balances.
+    #[serde(default)]
+    pub closing_aliases: HashMap<String, Vec<String>>,
+    pub prices: PriceConfig,
+    #[serde(default)]
+    pub allocation: Option<AllocationConfig>,
+    /// Files in the year's data dir with brokers' Form 1099-DA sales, as
+    /// CSV or JSON, to reconcile the dispositions against.
+    #[serde(default)]
+    pub form_1099da: Vec<String>,
+    /// Method-independent treatment choices for `Inventory::apply_deltas`.
+    #[serde(default)]
+    pub rules: inventory::Rules,
+    /// Rates the `mintax` inventory method weighs gains and losses by.
+    #[serde(default)]
+    pub tax_rates: inventory::TaxRates,
+    #[serde(default)]
+    pub link_exemptions: LinkExemptions,
+    /// Line in the capital gains report describing where prices came from.
+    pub price_description: String,
+    /// The Canadian return, for years that have one.
+    #[serde(default)]
+    pub canada: Option<CanadaConfig>,
+}
+
+impl YearConfig {
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Self = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
+    /// On-chain names whose balances make up `asset`'s opening balance.
+    pub fn opening_names(&self, asset: &str) -> Vec<String> {
+        alias_names(&self.opening_aliases, asset)
+    }
+
+    /// On-chain names whose balances make up `asset`'s closing balance.
+    pub fn closing_names(&self, asset: &str) -> Vec<String> {
+        alias_names(&self.closing_aliases, asset)
+    }
+
+    /// Whether an In is expected to have Outs linked to it. Ins that are
+    /// not (wraps, discoveries, known one-offs) are left out of the
+    /// unlinked-acquisition report.
+    pub fn needs_link(&self, delta: &deltas::Delta) -> bool {
+        let exempt = &self.link_exemptions;
+        delta.direction == deltas::Direction::In
+            && !exempt.ilks.contains(&delta.ilk)
+            && !exempt.ilk_assets.iter().any(|(ilk, asset)| *ilk == delta.ilk && *asset == delta.asset)
+            && !exempt.ilk_hosts.iter().any(|(ilk, host)| *ilk == delta.ilk && *host == delta.host)
+            && !exempt.identifiers.contains(&delta.identifier)
+    }
+}
+
+fn alias_names(aliases: &HashMap<String, Vec<String>>, asset: &str) -> Vec<String> {
+    match aliases.get(asset) {
+        Some(names) => names.clone(),
+        None => vec![asset.to_string()],
+    }
+}
+
+
+/// Where the year's opening inventory comes from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Opening {
+    /// First year tracked: every opening balance becomes a single zero-cost
+    /// lot dated Jan 1, with the opening aliases folded into their ticker.
+    ZeroCost,
+    /// The previous year's `end_inventory_us.json`, checked against this
+    /// year's opening balances.
+    Carried,
+    /// The previous year's pooled end inventory, split over wallets by the
+    /// allocation saved by the `allocate` step. Used for the first year
+    /// tracked per wallet.
+    Allocated,
+}
+
+/// Inputs to the Canadian return for the part of the year before leaving
+/// Canada, in CAD with each asset's cost pooled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CanadaConfig {
+    /// CAD prices, saved as `prices_CAD.json` by the `fetch-prices` step.
+    pub prices: PriceConfig,
+    /// Day of departure, `YYYY-MM-DD`. Deltas from this day on are left
+    /// out, and everything held is deemed disposed of at the previous
+    /// day's close.
+    pub departure: String,
+    /// File of CAD closing prices on the day before departure. LP tokens
+    /// have no market of their own, so each needs its value per token here.
+    pub close_prices: String,
+    /// Line in the Canadian report describing where prices came from.
+    pub price_description: String,
+}
+
+/// Inputs to the one-time allocation of pooled basis to wallets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AllocationConfig {
+    /// File in the year's data dir holding per-wallet balances at Jan 1.
+    pub wallet_balances: String,
+    /// File in the year's data dir assigning specific lots to wallets.
+    /// Without one, the oldest lots go to the largest balances.
+    #[serde(default)]
+    pub mapping: Option<String>,
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PriceConfig {
+    /// Request prices under tax tickers (ETH for WETH, ...) rather than the
+    /// on-chain names found in the deltas.
+    #[serde(default)]
+    pub tax_tickers: bool,
+    /// Assets to price even though no delta references them this year.
+    #[serde(default)]
+    pub extra_assets: Vec<String>,
+    pub source: PriceSource,
+    /// Secondary source used to fill in days missing from `source`.
+    #[serde(default)]
+    pub patch: Option<PricePatch>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PriceSource {
+    /// A directory of `{asset}.json` date -> price maps.
+    Dir { path: String },
+    /// The coingecko pro api, over the whole calendar year.
+    Coingecko { api_key_path: String, delay_millis: u64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PricePatch {
+    pub path: String,
+    /// Inclusive start date, `YYYY-MM-DD`.
+    pub from: String,
+    /// Exclusive end date, `YYYY-MM-DD`.
+    pub to: String,
+}
+
+
+/// Ins that do not need to be linked to an Out, see `YearConfig::needs_link`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LinkExemptions {
+    #[serde(default)]
+    pub ilks: Vec<deltas::Ilk>,
+    #[serde(default)]
+    pub ilk_assets: Vec<(deltas::Ilk, String)>,
+    #[serde(default)]
+    pub ilk_hosts: Vec<(deltas::Ilk, deltas::Host)>,
+    #[serde(default)]
+    pub identifiers: Vec<String>,
+}
@@ -1,21 +1,19 @@
 use serde::{Serialize, Deserialize};
 use std::error::Error;
 
+use crate::error;
+use crate::fees;
+use crate::income;
+use crate::inventory;
+use crate::loans;
+use crate::position_assets;
+use crate::lp_tokens;
 use crate::prices;
+use crate::specific_id;
 use crate::symbols;
 use chrono::TimeZone;
 use std::collections::{HashMap, HashSet};
 
-/// Returns true if the asset represents a Uniswap concentrated-liquidity
-/// position (V3 or V4). These are tracked as synthetic assets with an
-/// NFT-like identifier rather than a fungible token ticker, and need
-/// special handling throughout linking, cost basis, and revenue calculations.
-///
-/// Asset format: `UNI-V{3,4}-LIQUIDITY:{tokenId}_{token0}_{token1}_{feeOrPoolId}_{tickLower}_{tickUpper}`
-pub fn is_uni_cl_position(asset: &str) -> bool {
-    asset.starts_with("UNI-V3-LIQUIDITY") || asset.starts_with("UNI-V4-LIQUIDITY")
-}
-
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Deltas ( pub Vec<Delta> );
@@ -34,10 +32,12 @@ impl Deltas {
         Ok(())
     }
 
-    pub fn used_assets(&self) -> Vec<String> {
+    /// Assets to price: everything but positions and the tokens with a
+    /// `spam` id (see `Delta::spam_id`), which have no market.
+    pub fn used_assets(&self, spam: &HashSet<String>) -> Vec<String> {
         let mut uas = Vec::new();
         for delta in &self.0 {
-            if is_uni_cl_position(&delta.asset) {
+            if position_assets::is_position(&delta.asset) || spam.contains(&delta.spam_id()) {
                 continue
             };
             if !uas.contains(&delta.asset) {
@@ -47,9 +47,15 @@ impl Deltas {
         uas
     }
 
+    /// Spam ids of the tokens phishing attempts dropped on us.
+    pub fn spam_ids(&self) -> HashSet<String> {
+        self.0.iter().filter(|d| d.ilk == Ilk::PhishingAttempt).map(|d| d.spam_id()).collect()
+    }
+
     /// Groups all deltas into DeltaGroups and returns a LinkedDeltas.
     ///
     /// Ins-first algorithm:
+    /// Step 0: Mark payments between our own wallets as wallet transfers
     /// Step 1: Separate Ins and Outs, group Ins by identifier
     /// Step 2: Build indexes on In groups
     /// Step 3: Place each Out into the best matching In group
@@ -59,7 +65,8 @@ impl Deltas {
         let mut groups: HashMap<String, DeltaGroup> = HashMap::new();
         let mut outs: Vec<Delta> = Vec::new();
 
-        for delta in &self.0 {
+        let deltas = with_wallet_transfers(&self.0);
+        for delta in &deltas {
             match delta.direction {
                 Direction::In => {
                     groups.entry(delta.identifier.clone())
@@ -262,13 +269,18 @@ impl Deltas {
         result.extend(unmatched_outs.into_iter().map(|out| DeltaGroup { ins: vec![], outs: vec![out] }));
         result.sort_by_key(|g| g.timestamp());
 
+        // Only liquidity groups have several Ins: two tokens of principal,
+        // plus the two tokens of fees when they are collected with it.
         for group in &result {
-            assert!(group.ins.len() <= 2,
+            assert!(group.ins.len() <= 4,
                 "group with {} ins: {:?}", group.ins.len(), group.ins);
-            if group.ins.len() == 2 {
+            if group.ins.len() >= 2 {
                 assert!(group.ins.iter().all(|d|
                     d.ilk == Ilk::ManageLiquidity || d.ilk == Ilk::RemoveLiquidity || d.ilk == Ilk::SwapFees),
-                    "2-in group with non-liquidity ilks: {:?}", group.ins);
+                    "{}-in group with non-liquidity ilks: {:?}", group.ins.len(), group.ins);
+                let principal = group.ins.iter().filter(|d| d.ilk != Ilk::SwapFees).count();
+                assert!(principal <= 2 && group.ins.len() - principal <= 2,
+                    "liquidity group with more than two tokens: {:?}", group.ins);
             }
         }
 
@@ -276,6 +288,42 @@ impl Deltas {
     }
 }
 
+/// `deltas` with each payment out of one of our wallets that another of
+/// them received, in the same transaction and same quantity of the same
+/// tax ticker, marked as a `WalletTransfer` on both sides. Every account
+/// in the deltas is ours.
+fn with_wallet_transfers(deltas: &[Delta]) -> Vec<Delta> {
+    let mut marked = deltas.to_vec();
+    let mut receiving: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (i, d) in deltas.iter().enumerate() {
+        if d.direction == Direction::In && d.ilk == Ilk::Payment {
+            receiving.entry((d.identifier.clone(), symbols::delta_tax_ticker(d))).or_default().push(i);
+        }
+    }
+
+    let mut count = 0;
+    for (i, out) in deltas.iter().enumerate() {
+        if out.direction != Direction::Out || out.ilk != Ilk::Payment {
+            continue
+        }
+        let Some(candidates) = receiving.get_mut(&(out.identifier.clone(), symbols::delta_tax_ticker(out))) else {
+            continue
+        };
+        let found = candidates.iter().position(|&j| {
+            let d = &deltas[j];
+            (d.host != out.host || d.account != out.account) && (d.qty - out.qty).abs() <= specific_id::QTY_TOLERANCE
+        });
+        if let Some(k) = found {
+            let j = candidates.remove(k);
+            marked[i].ilk = Ilk::WalletTransfer;
+            marked[j].ilk = Ilk::WalletTransfer;
+            count += 1;
+        }
+    }
+    println!("  step 0: {} payments between our own wallets marked as wallet transfers", count);
+    marked
+}
+
 /// A group of related deltas from the same transaction/event.
 /// Ins are acquisitions, Outs are dispositions/fees that support the Ins.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -295,50 +343,111 @@ impl DeltaGroup {
             .unwrap_or(0)
     }
 
-    /// All deltas in the group, ins first then outs
-    pub fn all_deltas(&self) -> impl Iterator<Item = &Delta> {
-        self.ins.iter().chain(self.outs.iter())
+    /// How `fee`, one of the group's Outs, is treated under `policy`, after
+    /// falling back where the configured treatment has nothing to apply to:
+    /// capitalizing needs an In that takes a basis, reducing proceeds a
+    /// non-fee Out that has proceeds.
+    pub fn fee_treatment(&self, fee: &Delta, quote_currency: &str, policy: &fees::FeePolicy) -> fees::FeeTreatment {
+        // The two sides of a wallet transfer move lots rather than take
+        // or give up basis.
+        let has_basis_in = self.ins.iter().any(|d| d.asset != quote_currency && d.ilk != Ilk::WalletTransfer);
+        let has_principal = self.outs.iter().any(|d| !d.ilk.is_fee() && d.asset != quote_currency && d.ilk != Ilk::WalletTransfer);
+        match policy.treatment(&fee.ilk) {
+            fees::FeeTreatment::Capitalize if has_basis_in => fees::FeeTreatment::Capitalize,
+            fees::FeeTreatment::Capitalize | fees::FeeTreatment::ReduceProceeds if has_principal => fees::FeeTreatment::ReduceProceeds,
+            fees::FeeTreatment::ReduceProceeds if has_basis_in => fees::FeeTreatment::Capitalize,
+            _ => fees::FeeTreatment::Expense,
+        }
+    }
+
+    /// Whether `out`'s value belongs in the basis of the group's Ins. Loan
+    /// repayments and collateral don't pay for anything.
+    fn capitalizes(&self, out: &Delta, quote_currency: &str, rules: &inventory::Rules) -> bool {
+        if loans::role(out).is_some() {
+            return false
+        }
+        !out.ilk.is_fee() || self.fee_treatment(out, quote_currency, &rules.fees) == fees::FeeTreatment::Capitalize
+    }
+
+    /// Whether `delta` is an In of a deposited token coming back alongside
+    /// LP tokens, as when a router refunds what the pool ratio didn't need.
+    fn is_lp_refund(&self, delta: &Delta) -> bool {
+        let ticker = symbols::delta_tax_ticker(delta);
+        delta.direction == Direction::In
+            && !lp_tokens::is_lp_token(&delta.asset)
+            && self.ins.iter().any(|d| lp_tokens::is_lp_token(&d.asset))
+            && self.outs.iter().any(|out| !out.ilk.is_fee() && symbols::delta_tax_ticker(out) == ticker)
     }
 
     /// Cost basis for an In delta = sum of related Out values.
-    /// Replicates the logic from the old index_cost.
-    pub fn cost_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices) -> f64 {
-        assert!(delta.direction == Direction::In);
+    /// Replicates the logic from the old index_cost. Income Ins, per
+    /// `rules.income`, add their own fair market value; fees only count
+    /// when `rules.fees` capitalizes them.
+    pub fn cost_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices, rules: &inventory::Rules) -> Result<f64, error::CalcError> {
+        check(delta.direction == Direction::In, delta, "cost asked for an Out")?;
 
-        if delta.asset == quote_currency {
+        let cost = if delta.asset == quote_currency {
+            0.0
+        } else if delta.ilk == Ilk::PhishingAttempt {
+            // Spam tokens sent to us: no basis, and never priced.
             0.0
-        } else if delta.ilk == Ilk::RemoveLiquidity {
-            delta.value(quote_currency, prices)
-        } else if delta.ilk == Ilk::ManageLiquidity && !is_uni_cl_position(&delta.asset) {
+        } else if lp_tokens::is_lp_token(&delta.asset) {
+            // LP tokens from adding liquidity: the value of the tokens
+            // deposited for them, less any the pool sent back. They have
+            // no price of their own.
+            let mut c = 0f64;
+            for out in self.outs.iter().filter(|out| self.capitalizes(out, quote_currency, rules)) {
+                check(out.direction == Direction::Out, out, "linked as an Out but is an In")?;
+                c += out.value(quote_currency, prices)?;
+            }
+            for refund in self.ins.iter().filter(|d| self.is_lp_refund(d)) {
+                c -= refund.value(quote_currency, prices)?;
+            }
+            c
+        } else if self.is_lp_refund(delta) {
+            // Part of a deposit the pool didn't take: its own value, which
+            // the LP tokens' basis leaves out.
+            delta.value(quote_currency, prices)?
+        } else if delta.ilk == Ilk::RemoveLiquidity || self.outs.iter().any(|out| lp_tokens::is_lp_token(&out.asset)) {
+            // Underlyings returned for LP tokens, whatever the ilk, start
+            // fresh lots at their value.
+            delta.value(quote_currency, prices)?
+        } else if delta.ilk == Ilk::ManageLiquidity && !position_assets::is_position(&delta.asset) {
             // Token deposited into a CL position — cost is the token's value
             // plus any gas fees linked to it (but not the position asset itself).
-            let mut c = delta.value(quote_currency, prices);
+            let mut c = delta.value(quote_currency, prices)?;
             for out in &self.outs {
-                if !is_uni_cl_position(&out.asset) && (out.ilk == Ilk::ManageLiquidityGas || out.ilk == Ilk::ManageLiquidityFailGas) {
-                    assert!(out.direction == Direction::Out);
-                    c += out.value(quote_currency, prices);
+                if !position_assets::is_position(&out.asset) && (out.ilk == Ilk::ManageLiquidityGas || out.ilk == Ilk::ManageLiquidityFailGas) && self.capitalizes(out, quote_currency, rules) {
+                    check(out.direction == Direction::Out, out, "linked as an Out but is an In")?;
+                    c += out.value(quote_currency, prices)?;
                 }
             }
             c
         } else if delta.ilk == Ilk::ChangeMakerVault {
-            assert!(delta.asset == "DAI");
-            delta.value(quote_currency, prices)
-        } else if delta.ilk == Ilk::Loan {
-            assert!(delta.asset == "ETH");
-            delta.value(quote_currency, prices)
-        } else if delta.ilk == Ilk::Airdrop {
-            let mut c = delta.value(quote_currency, prices);
-            for out in &self.outs {
-                c += out.value(quote_currency, prices);
+            check(delta.asset == "DAI", delta, "maker vault change in something other than DAI")?;
+            delta.value(quote_currency, prices)?
+        } else if loans::role(delta) == Some(loans::LoanRole::Borrow) {
+            // Borrowing isn't income, but what was borrowed takes its
+            // value as basis.
+            delta.value(quote_currency, prices)?
+        } else if rules.income.category(delta).is_some() {
+            // Income is its own basis. The Outs go with it only when every
+            // In is income; otherwise they paid for the others, as when
+            // liquidity fees are collected along with principal.
+            let mut c = delta.value(quote_currency, prices)?;
+            if self.ins.iter().all(|d| rules.income.category(d).is_some()) {
+                for out in self.outs.iter().filter(|out| self.capitalizes(out, quote_currency, rules)) {
+                    c += out.value(quote_currency, prices)?;
+                }
             }
             c
         } else if delta.ilk == Ilk::SwapFees {
             0.0
         } else {
             let mut c = 0f64;
-            for out in &self.outs {
-                assert!(out.direction == Direction::Out);
-                c += out.value(quote_currency, prices);
+            for out in self.outs.iter().filter(|out| self.capitalizes(out, quote_currency, rules)) {
+                check(out.direction == Direction::Out, out, "linked as an Out but is an In")?;
+                c += out.value(quote_currency, prices)?;
             }
 
             //////////2023TEST////////////////////
@@ -354,61 +463,113 @@ impl DeltaGroup {
             //////////2024TEST////////////////////
 
             c
-        }
+        };
+        Ok(cost)
     }
 
-    /// Income for an In delta (airdrops, staking, etc.)
-    pub fn income_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices) -> f64 {
-        assert!(delta.direction == Direction::In);
-        if delta.ilk == Ilk::Airdrop {
-            delta.value(quote_currency, prices)
-        } else if delta.ilk == Ilk::TradeFee && delta.direction == Direction::In {
-            delta.value(quote_currency, prices)
-        } else {
-            0_f64
+    /// Income for an In delta (airdrops, staking, etc.) at fair market
+    /// value, with its category, or `None` if `rules.income` doesn't
+    /// classify it as income.
+    pub fn income_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices, rules: &inventory::Rules) -> Result<Option<(income::IncomeCategory, f64)>, error::CalcError> {
+        check(delta.direction == Direction::In, delta, "income asked for an Out")?;
+        if delta.ilk == Ilk::PhishingAttempt {
+            return Ok(None)
+        }
+        match rules.income.category(delta) {
+            Some(category) => Ok(Some((category, delta.value(quote_currency, prices)?))),
+            None => Ok(None),
         }
     }
 
     /// Revenue for an Out delta = value of the disposition, potentially
-    /// adjusted by linked In values (e.g. when sold for quote currency).
-    pub fn revenue_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices) -> f64 {
-        assert!(delta.direction == Direction::Out);
+    /// adjusted by linked In values (e.g. when sold for quote currency),
+    /// less its share of the fees `rules.fees` charges against proceeds.
+    /// A fee's own revenue is its value.
+    pub fn revenue_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices, rules: &inventory::Rules) -> Result<f64, error::CalcError> {
+        check(delta.direction == Direction::Out, delta, "revenue asked for an In")?;
 
-        if delta.asset == quote_currency {
-            0.0
-        } else if delta.ilk == Ilk::RemoveLiquidity {
+        if delta.asset == quote_currency || delta.ilk == Ilk::Loss {
+            return Ok(0.0)
+        } else if delta.ilk.is_fee() {
+            return delta.value(quote_currency, prices)
+        }
+        Ok(self.gross_revenue_for(delta, quote_currency, prices)? - self.proceeds_fees(delta, quote_currency, prices, rules)?)
+    }
+
+    /// Revenue for a non-fee Out before any fees come off it.
+    fn gross_revenue_for(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices) -> Result<f64, error::CalcError> {
+        let revenue = if delta.ilk == Ilk::RemoveLiquidity || lp_tokens::is_lp_token(&delta.asset) {
+            // LP tokens given back for the underlyings, whatever the ilk.
             let mut c = 0f64;
-            for in_delta in &self.ins {
-                c += in_delta.value(quote_currency, prices);
+            for in_delta in self.ins.iter().filter(|d| d.ilk != Ilk::SwapFees && !lp_tokens::is_lp_token(&d.asset)) {
+                c += in_delta.value(quote_currency, prices)?;
             }
             c
-        } else if delta.ilk == Ilk::ManageLiquidity && is_uni_cl_position(&delta.asset) {
-            // Removing a CL position — revenue is the value of the tokens
-            // received back (the linked ManageLiquidity In deltas).
+        } else if delta.ilk == Ilk::ManageLiquidity && position_assets::is_position(&delta.asset) {
+            // Removing a CL position — revenue is the value of the principal
+            // received back (the linked ManageLiquidity In deltas). Fees
+            // collected in the same transaction come as SwapFees Ins, which
+            // are income rather than proceeds.
             let mut c = 0f64;
-            for in_delta in &self.ins {
-                assert!(in_delta.ilk == Ilk::ManageLiquidity);
-                assert!(in_delta.direction == Direction::In);
-                c += in_delta.value(quote_currency, prices);
+            for in_delta in self.ins.iter().filter(|d| d.ilk != Ilk::SwapFees) {
+                check(in_delta.ilk == Ilk::ManageLiquidity, in_delta, "position removal returned something other than ManageLiquidity")?;
+                check(in_delta.direction == Direction::In, in_delta, "linked as an In but is an Out")?;
+                c += in_delta.value(quote_currency, prices)?;
             }
             c
         } else {
-            let mut r = delta.value(quote_currency, prices);
+            let mut r = delta.value(quote_currency, prices)?;
             // Check if there's a quote-currency In (e.g. sold for USD)
             for in_delta in &self.ins {
                 if in_delta.asset == quote_currency && in_delta.direction == Direction::In {
                     r = in_delta.qty;
                 }
             }
-            // Subtract quote-currency TradeFee outs
             for out in &self.outs {
                 if out.asset == quote_currency && out.direction == Direction::Out {
-                    assert!(out.ilk == Ilk::TradeFee);
-                    r -= out.qty;
+                    check(out.ilk == Ilk::TradeFee, out, "quote currency Out that isn't a trade fee")?;
                 }
             }
             r
+        };
+        Ok(revenue)
+    }
+
+    /// `delta`'s share of the group's fees that reduce proceeds, split
+    /// over the non-fee Outs by their revenue before fees.
+    fn proceeds_fees(&self, delta: &Delta, quote_currency: &str, prices: &prices::Prices, rules: &inventory::Rules) -> Result<f64, error::CalcError> {
+        let mut fees = 0f64;
+        for out in &self.outs {
+            if out.ilk.is_fee() && self.fee_treatment(out, quote_currency, &rules.fees) == fees::FeeTreatment::ReduceProceeds {
+                fees += out.value(quote_currency, prices)?;
+            }
+        }
+        if fees == 0.0 {
+            return Ok(0.0)
         }
+
+        let principals: Vec<&Delta> = self.outs.iter().filter(|d| !d.ilk.is_fee() && d.asset != quote_currency && d.ilk != Ilk::Loss).collect();
+        let mut total = 0f64;
+        for out in &principals {
+            total += self.gross_revenue_for(out, quote_currency, prices)?;
+        }
+        let share = if total > 0.0 {
+            self.gross_revenue_for(delta, quote_currency, prices)? / total
+        } else {
+            1.0 / principals.len() as f64
+        };
+        Ok(fees * share)
+    }
+}
+
+
+/// Errors with `reason` unless `ok`, for the shape checks in the
+/// cost, income and revenue rules.
+fn check(ok: bool, delta: &Delta, reason: &str) -> Result<(), error::CalcError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(error::CalcError::UnexpectedDelta { asset: delta.asset.clone(), reason: reason.to_string() })
     }
 }
 
@@ -431,21 +592,6 @@ impl LinkedDeltas {
         Ok(())
     }
 
-    pub fn used_assets(&self) -> Vec<String> {
-        let mut uas = Vec::new();
-        for group in &self.0 {
-            for delta in group.all_deltas() {
-                if is_uni_cl_position(&delta.asset) {
-                    continue
-                };
-                if !uas.contains(&delta.asset) {
-                    uas.push(delta.asset.clone());
-                }
-            }
-        }
-        uas
-    }
-
     /// Reassign quote-currency trade fees from the In side to the Out side of their group.
     /// When a Match In is in the quote currency, its TradeFee should reduce the Out's revenue
     /// rather than increase the In's cost.
@@ -501,24 +647,42 @@ pub struct Delta {
     pub account: String,
     pub identifier: String,
     #[serde(default)]
-    pub linked_to: Vec<usize>
+    pub linked_to: Vec<usize>,
+    /// Token contract, where the source gives one. Tells apart tokens
+    /// that share a symbol, as phishing airdrops copying a real one do.
+    #[serde(default)]
+    pub contract: Option<String>,
 }
 
 impl Delta {
 
-    pub fn value (&self, quote_currency: &str, prices: &prices::Prices) -> f64 {
+    pub fn value (&self, quote_currency: &str, prices: &prices::Prices) -> Result<f64, error::CalcError> {
 
         let symbol = symbols::onchain_ticker_to_tax_ticker(&self.asset);
 
         let value = if self.asset == quote_currency {
             self.qty
         } else {
-            let price = prices.price_at_millis(&symbol, self.timestamp);
+            let price = prices.price_at_millis(&symbol, self.timestamp)?;
             self.qty * price
 
         };
-        value
+        Ok(value)
     }
+
+    /// Identity of the token, should it be spam: its host and contract, or
+    /// its on-chain name where there is no contract. Spam lots are held
+    /// under this key, so the mark carries over with the inventory.
+    pub fn spam_id(&self) -> String {
+        format!("{}{}:{}", SPAM_PREFIX, self.host.to_string(), self.contract.as_deref().unwrap_or(&self.asset))
+    }
+}
+
+const SPAM_PREFIX: &str = "SPAM:";
+
+/// Whether an inventory key holds spam lots.
+pub fn is_spam_id(key: &str) -> bool {
+    key.starts_with(SPAM_PREFIX)
 }
 
 
@@ -567,7 +731,7 @@ impl Host {
             Host::Binance => true,
             Host::BinanceUs => true,
             Host::Kucoin => true,
-            Host::DydxSoloMargin => panic!(""),
+            Host::DydxSoloMargin => false,
             Host::PolygonPos => false,
             Host::FtxUs => true,
             Host::Zksync => false,
@@ -680,4 +844,196 @@ pub enum Ilk {
     Reward,
     RewardClaimGas,
     RewardClaimFailGas,
+    /// A move between two of our own wallets: an Out on the sending wallet
+    /// and an In on the receiving one, sharing an identifier. `Deltas::link`
+    /// marks matching Payment pairs with it.
+    WalletTransfer,
+}
+
+impl Ilk {
+    /// Gas, trade, bridge and withdrawal fees: Outs paid for a service
+    /// rather than exchanged for the group's Ins.
+    pub fn is_fee(&self) -> bool {
+        matches!(self,
+            Ilk::SwapGas
+            | Ilk::SwapFailGas
+            | Ilk::WalletToWalletGas
+            | Ilk::WrapEthGas
+            | Ilk::WrapEthFailGas
+            | Ilk::UnwrapEthGas
+            | Ilk::UnwrapEthFailGas
+            | Ilk::ApproveGas
+            | Ilk::ApproveFailGas
+            | Ilk::PaymentGas
+            | Ilk::Erc20TransferFailGas
+            | Ilk::AirdropClaimGas
+            | Ilk::AirdropClaimFailGas
+            | Ilk::TokenMigrationGas
+            | Ilk::DeployContractGas
+            | Ilk::DeployContractFailGas
+            | Ilk::RemoveLiquidityGas
+            | Ilk::AllowOnContractGas
+            | Ilk::PayMinerDirecltyGas
+            | Ilk::CreateMakerVaultGas
+            | Ilk::ChangeMakerVaultGas
+            | Ilk::ChangeMakerVaultFailGas
+            | Ilk::DydxDepositGas
+            | Ilk::OperateSoloMarginGas
+            | Ilk::OperateSoloMarginFailGas
+            | Ilk::BridgeGas
+            | Ilk::BridgeFee
+            | Ilk::MalformedTxGas
+            | Ilk::TradeFee
+            | Ilk::WithdrawalFee
+            | Ilk::CoinbaseDepositGas
+            | Ilk::BinanceDepositGas
+            | Ilk::KucoinDepositGas
+            | Ilk::DelegateGas
+            | Ilk::FtxusDepositGas
+            | Ilk::ManageLiquidityGas
+            | Ilk::ManageLiquidityFailGas
+            | Ilk::RewardClaimGas
+            | Ilk::RewardClaimFailGas
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn pendle_sale_is_valued_like_a_token() {
+        let t = fixtures::noon(fixtures::DAY);
+        let pt = "PENDLE-PT:0xmarket_stETH_2025-06-26";
+        let group = DeltaGroup {
+            ins: vec![fixtures::delta(t, Direction::In, Ilk::Swap, "WETH", 2.0)],
+            outs: vec![fixtures::delta(t, Direction::Out, Ilk::Swap, pt, 3.0)],
+        };
+        let prices = fixtures::prices(&[(pt, fixtures::DAY, 2000.0), ("ETH", fixtures::DAY, 3100.0)]);
+        let revenue = group.revenue_for(&group.outs[0], "USD", &prices, &inventory::Rules::default()).unwrap();
+        assert_eq!(revenue, 6000.0);
+    }
+
+    #[test]
+    fn unpriced_position_is_an_error_not_a_panic() {
+        let t = fixtures::noon(fixtures::DAY);
+        let position = "UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200";
+        let out = fixtures::delta(t, Direction::Out, Ilk::Payment, position, 1.0);
+        assert!(matches!(out.value("USD", &fixtures::prices(&[])), Err(error::CalcError::MissingPrice { .. })));
+    }
+
+    #[test]
+    fn position_removal_is_principal_returned() {
+        let t = fixtures::noon(fixtures::DAY);
+        let position = "UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200";
+        let group = DeltaGroup {
+            ins: vec![
+                fixtures::delta(t, Direction::In, Ilk::ManageLiquidity, "WETH", 1.0),
+                fixtures::delta(t, Direction::In, Ilk::ManageLiquidity, "USDC", 3000.0),
+                fixtures::delta(t, Direction::In, Ilk::SwapFees, "USDC", 50.0),
+            ],
+            outs: vec![fixtures::delta(t, Direction::Out, Ilk::ManageLiquidity, position, 1000.0)],
+        };
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0), ("USDC", fixtures::DAY, 1.0)]);
+        let revenue = group.revenue_for(&group.outs[0], "USD", &prices, &inventory::Rules::default()).unwrap();
+        assert_eq!(revenue, 6000.0);
+    }
+
+    #[test]
+    fn fees_off_a_position_removal_split_by_revenue() {
+        let t = fixtures::noon(fixtures::DAY);
+        let position = "UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200";
+        let group = DeltaGroup {
+            ins: vec![fixtures::delta(t, Direction::In, Ilk::ManageLiquidity, "USDC", 3000.0)],
+            outs: vec![
+                fixtures::delta(t, Direction::Out, Ilk::ManageLiquidity, position, 1000.0),
+                fixtures::delta(t, Direction::Out, Ilk::ManageLiquidityGas, "WETH", 0.01),
+            ],
+        };
+        let rules = inventory::Rules {
+            fees: fees::FeePolicy { default: fees::FeeTreatment::ReduceProceeds, by_ilk: Vec::new() },
+            ..Default::default()
+        };
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0), ("USDC", fixtures::DAY, 1.0)]);
+        let revenue = group.revenue_for(&group.outs[0], "USD", &prices, &rules).unwrap();
+        assert_eq!(revenue, 2970.0);
+    }
+
+    #[test]
+    fn link_marks_payments_between_own_wallets() {
+        let t = fixtures::noon(fixtures::DAY);
+        let sent = Delta { account: "0xa".to_string(), ..fixtures::delta(t, Direction::Out, Ilk::Payment, "USDC", 100.0) };
+        let received = Delta { account: "0xb".to_string(), ..fixtures::delta(t, Direction::In, Ilk::Payment, "USDC", 100.0) };
+        let gas = Delta { account: "0xa".to_string(), ..fixtures::delta(t, Direction::Out, Ilk::WalletToWalletGas, "ETH", 0.001) };
+        let linked = Deltas(vec![sent, received, gas]).link();
+        assert_eq!(linked.0.len(), 1);
+        let group = &linked.0[0];
+        assert_eq!(group.ins[0].ilk, Ilk::WalletTransfer);
+        assert!(group.outs.iter().any(|d| d.ilk == Ilk::WalletTransfer));
+        assert_eq!(group.fee_treatment(&group.outs[1], "USD", &fees::FeePolicy::default()), fees::FeeTreatment::Expense);
+    }
+
+    #[test]
+    fn link_leaves_payments_that_differ() {
+        let t = fixtures::noon(fixtures::DAY);
+        let sent = Delta { account: "0xa".to_string(), ..fixtures::delta(t, Direction::Out, Ilk::Payment, "USDC", 100.0) };
+        let received = Delta { account: "0xb".to_string(), ..fixtures::delta(t, Direction::In, Ilk::Payment, "USDC", 99.0) };
+        let linked = Deltas(vec![sent, received]).link();
+        assert!(linked.0.iter().flat_map(|g| g.ins.iter().chain(&g.outs)).all(|d| d.ilk == Ilk::Payment));
+    }
+
+    #[test]
+    fn lp_basis_leaves_out_refunds() {
+        let t = fixtures::noon(fixtures::DAY);
+        let group = DeltaGroup {
+            ins: vec![
+                fixtures::delta(t, Direction::In, Ilk::ManageLiquidity, "UNI-V2:WETH-USDC", 0.5),
+                fixtures::delta(t, Direction::In, Ilk::ManageLiquidity, "USDC", 10.0),
+            ],
+            outs: vec![
+                fixtures::delta(t, Direction::Out, Ilk::ManageLiquidity, "WETH", 1.0),
+                fixtures::delta(t, Direction::Out, Ilk::ManageLiquidity, "USDC", 3010.0),
+            ],
+        };
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0), ("USDC", fixtures::DAY, 1.0)]);
+        let rules = inventory::Rules::default();
+        assert_eq!(group.cost_for(&group.ins[0], "USD", &prices, &rules).unwrap(), 6000.0);
+        assert_eq!(group.cost_for(&group.ins[1], "USD", &prices, &rules).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn lp_removal_returns_underlyings_at_their_value() {
+        let t = fixtures::noon(fixtures::DAY);
+        let group = DeltaGroup {
+            ins: vec![
+                fixtures::delta(t, Direction::In, Ilk::Swap, "WETH", 1.0),
+                fixtures::delta(t, Direction::In, Ilk::Swap, "USDC", 3000.0),
+            ],
+            outs: vec![fixtures::delta(t, Direction::Out, Ilk::Swap, "UNI-V2:WETH-USDC", 0.5)],
+        };
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0), ("USDC", fixtures::DAY, 1.0)]);
+        let rules = inventory::Rules::default();
+        assert_eq!(group.cost_for(&group.ins[0], "USD", &prices, &rules).unwrap(), 3000.0);
+        assert_eq!(group.cost_for(&group.ins[1], "USD", &prices, &rules).unwrap(), 3000.0);
+        assert_eq!(group.revenue_for(&group.outs[0], "USD", &prices, &rules).unwrap(), 6000.0);
+    }
+
+    #[test]
+    fn used_assets_leave_out_spam_by_contract() {
+        let t = fixtures::noon(fixtures::DAY);
+        let with_contract = |ilk: Ilk, asset: &str, contract: &str| Delta { contract: Some(contract.to_string()), ..fixtures::delta(t, Direction::In, ilk, asset, 1.0) };
+        let deltas = Deltas(vec![
+            with_contract(Ilk::PhishingAttempt, "USDC", "0xfake"),
+            with_contract(Ilk::Swap, "USDC", "0xreal"),
+            with_contract(Ilk::PhishingAttempt, "SCAM", "0xscam"),
+        ]);
+        let mut spam = deltas.spam_ids();
+        assert_eq!(deltas.used_assets(&spam), vec!["USDC".to_string()]);
+
+        let carried = Deltas(vec![Delta { direction: Direction::Out, ilk: Ilk::Payment, ..with_contract(Ilk::Swap, "OLDSCAM", "0xold") }]);
+        spam.insert(carried.0[0].spam_id());
+        assert!(carried.used_assets(&spam).is_empty());
+    }
 }
@@ -0,0 +1,127 @@
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use crate::deltas;
+use crate::inventory;
+use chrono::{TimeZone, Utc};
+
+
+/// The part of one disposal matched to one lot. A disposal split across
+/// several lots has one record per lot, and the records' `qty` and
+/// `proceeds` add up to the disposing delta's quantity and revenue.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Disposition {
+    /// Tax ticker.
+    pub asset: String,
+    pub qty: f64,
+    /// Unix millis of the disposal.
+    pub disposed: u64,
+    /// Unix millis of the lot's acquisition.
+    pub acquired: u64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub long_term: bool,
+    pub inventory_method: String,
+    pub acquisition_identifier: Option<String>,
+    pub acquisition_host: Option<deltas::Host>,
+    pub acquisition_account: Option<String>,
+    pub disposition_identifier: String,
+    pub disposition_ilk: deltas::Ilk,
+    pub disposition_host: deltas::Host,
+    pub disposition_account: String,
+}
+
+impl Disposition {
+
+    /// `qty` of `lot` disposed of by `delta` for `proceeds`. `qty` can
+    /// differ from `lot.qty` by float error, which the last record of a
+    /// disposal absorbs.
+    pub fn new(asset: &str, delta: &deltas::Delta, lot: &inventory::Lot, qty: f64, proceeds: f64, long_term: bool, method: inventory::InventoryMethod) -> Self {
+        Self {
+            asset: asset.to_string(),
+            qty,
+            disposed: delta.timestamp,
+            acquired: lot.timestamp,
+            proceeds,
+            cost_basis: lot.cost,
+            long_term,
+            inventory_method: method.label(),
+            acquisition_identifier: lot.identifier.clone(),
+            acquisition_host: lot.host.clone(),
+            acquisition_account: lot.account.clone(),
+            disposition_identifier: delta.identifier.clone(),
+            disposition_ilk: delta.ilk.clone(),
+            disposition_host: delta.host.clone(),
+            disposition_account: delta.account.clone(),
+        }
+    }
+
+    pub fn gain(&self) -> f64 {
+        self.proceeds - self.cost_basis
+    }
+
+    /// A theft or scam loss rather than a sale or exchange.
+    pub fn is_casualty_loss(&self) -> bool {
+        self.disposition_ilk == deltas::Ilk::Loss
+    }
+}
+
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Dispositions ( pub Vec<Disposition> );
+
+impl Dispositions {
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Self = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
+    pub fn save (&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let json_string = serde_json::to_string(&self)?;
+        std::fs::write(path, &json_string)?;
+        Ok(())
+    }
+
+    pub fn to_csv(&self, quote_currency: &str) -> String {
+        let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        let mut csv = format!("asset,quantity,disposition_date,acquisition_date,proceeds_{q},cost_basis_{q},capital_gain_{q},term,inventory_method,acquisition_identifier,acquisition_host,acquisition_account,disposition_identifier,disposition_ilk,disposition_host,disposition_account\n", q = quote_currency);
+        for d in &self.0 {
+            csv += &format!(
+                "{},{:.8},{},{},{:.8},{:.8},{:.8},{},{},{},{},{},{},{:?},{:?},{}\n",
+                d.asset,
+                d.qty,
+                date(d.disposed),
+                date(d.acquired),
+                d.proceeds,
+                d.cost_basis,
+                d.gain(),
+                if d.long_term { "long" } else { "short" },
+                d.inventory_method,
+                d.acquisition_identifier.clone().unwrap_or_default(),
+                d.acquisition_host.as_ref().map(|host| format!("{:?}", host)).unwrap_or_default(),
+                d.acquisition_account.clone().unwrap_or_default(),
+                d.disposition_identifier,
+                d.disposition_ilk,
+                d.disposition_host,
+                d.disposition_account,
+            );
+        }
+        csv
+    }
+
+    /// The theft and scam losses, one line per lot, and their total.
+    pub fn casualty_report(&self) -> String {
+        let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().format("%F").to_string();
+
+        let mut report = String::new();
+        let mut total = 0f64;
+        for d in self.0.iter().filter(|d| d.is_casualty_loss()) {
+            report += &format!(" {} {:.8} {} acquired {} on {:?}:{}, basis {:.8} ({})\n", date(d.disposed), d.qty, d.asset, date(d.acquired), d.disposition_host, d.disposition_account, d.cost_basis, d.disposition_identifier);
+            total -= d.gain();
+        }
+        report += &format!(" total loss: {:.8}\n", total);
+        report
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use crate::deltas;
+use chrono::{TimeZone, Utc};
+
+
+/// A problem the calculation engine found in its inputs. `apply_deltas`
+/// keeps going past a bad delta so one run reports every problem, each
+/// wrapped in `CalcError::Delta` with the delta and group it came from.
+#[derive(Clone, Debug)]
+pub enum CalcError {
+    /// No price for `asset` on `date` (`YYYY-MM-DD`).
+    MissingPrice { asset: String, date: String },
+    /// A delta whose shape the cost, revenue or income rules don't cover,
+    /// e.g. an Out in a place only Ins are expected.
+    UnexpectedDelta { asset: String, reason: String },
+    /// A lot selection or wallet transfer that doesn't match the lots held.
+    Lots { asset: String, reason: String },
+    /// An opening inventory holding a negative lot, from a year calculated
+    /// before shortfalls were tracked.
+    NegativeOpeningLot { asset: String },
+    /// Inputs to the run as a whole that don't fit together.
+    Setup { reason: String },
+    /// Any of the above, raised while processing `delta` in group `group`
+    /// (its index in the linked deltas).
+    Delta { group: usize, delta: Box<deltas::Delta>, source: Box<CalcError> },
+}
+
+impl CalcError {
+    /// Attaches the delta and group being processed.
+    pub fn at(self, group: usize, delta: &deltas::Delta) -> Self {
+        CalcError::Delta { group, delta: Box::new(delta.clone()), source: Box::new(self) }
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::MissingPrice { asset, date } => write!(f, "no {} price on {}", asset, date),
+            CalcError::UnexpectedDelta { asset, reason } => write!(f, "{}: {}", asset, reason),
+            CalcError::Lots { asset, reason } => write!(f, "{}: {}", asset, reason),
+            CalcError::NegativeOpeningLot { asset } => write!(f, "opening inventory holds a negative {} lot; recalculate the year it was carried from", asset),
+            CalcError::Setup { reason } => write!(f, "{}", reason),
+            CalcError::Delta { group, delta, source } => write!(
+                f,
+                "group {}, {:?} {:?} {} {} on {:?}:{} at {} ({}): {}",
+                group,
+                delta.ilk,
+                delta.direction,
+                delta.qty,
+                delta.asset,
+                delta.host,
+                delta.account,
+                Utc.timestamp_millis_opt(delta.timestamp as i64).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                delta.identifier,
+                source,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CalcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CalcError::Delta { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Every problem found in one run.
+#[derive(Clone, Debug)]
+pub struct CalcErrors(pub Vec<CalcError>);
+
+impl fmt::Display for CalcErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} problem(s) in the calculation:", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "\n  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CalcErrors {}
+
+impl From<CalcError> for CalcErrors {
+    fn from(err: CalcError) -> Self {
+        CalcErrors(vec![err])
+    }
+}
@@ -0,0 +1,68 @@
+use serde::{Serialize, Deserialize};
+use crate::deltas;
+
+
+/// What a fee Out's value does for tax purposes. Paying a fee in a crypto
+/// asset is a disposal of that asset at fair market value in every case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeeTreatment {
+    /// Added to the basis of the group's Ins.
+    Capitalize,
+    /// Subtracted from the proceeds of the group's other Outs.
+    ReduceProceeds,
+    /// In neither; a deductible expense on its own.
+    Expense,
+}
+
+/// Fee treatment by `Ilk`. Fees whose ilk has no entry get `default`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeePolicy {
+    #[serde(default = "FeePolicy::default_treatment")]
+    pub default: FeeTreatment,
+    #[serde(default)]
+    pub by_ilk: Vec<(deltas::Ilk, FeeTreatment)>,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self { default: Self::default_treatment(), by_ilk: vec![] }
+    }
+}
+
+impl FeePolicy {
+    fn default_treatment() -> FeeTreatment {
+        FeeTreatment::Capitalize
+    }
+
+    /// The configured treatment of a fee of `ilk`.
+    pub fn treatment(&self, ilk: &deltas::Ilk) -> FeeTreatment {
+        self.by_ilk.iter().find(|(i, _)| i == ilk).map(|(_, t)| *t).unwrap_or(self.default)
+    }
+}
+
+/// Fee value for the year under each treatment, in the quote currency.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FeeTotals {
+    pub capitalized: f64,
+    pub reduced_proceeds: f64,
+    pub expensed: f64,
+}
+
+impl FeeTotals {
+
+    pub fn add(&mut self, treatment: FeeTreatment, amount: f64) {
+        match treatment {
+            FeeTreatment::Capitalize => self.capitalized += amount,
+            FeeTreatment::ReduceProceeds => self.reduced_proceeds += amount,
+            FeeTreatment::Expense => self.expensed += amount,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        report += &format!(" added to basis: {:.8}\n", self.capitalized);
+        report += &format!(" subtracted from proceeds: {:.8}\n", self.reduced_proceeds);
+        report += &format!(" deductible expense: {:.8}\n", self.expensed);
+        report
+    }
+}
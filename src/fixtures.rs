@@ -0,0 +1,77 @@
+//! Values tests build over and over, with the fields a test doesn't care
+//! about filled in.
+
+use std::collections::HashMap;
+use crate::deltas;
+use crate::disposition;
+use crate::inventory;
+use crate::prices;
+use chrono::TimeZone;
+
+
+/// The day tests happen on when the day doesn't matter. Outside 2023 and
+/// 2024, which `cost_for` has test multipliers for.
+pub const DAY: &str = "2022-03-01";
+
+/// Unix millis of `datetime`, `YYYY-MM-DD HH:MM`, in `time_zone`.
+pub fn millis_in(time_zone: chrono_tz::Tz, datetime: &str) -> u64 {
+    let naive = chrono::NaiveDateTime::parse_from_str(datetime, "%F %R").unwrap();
+    time_zone.from_local_datetime(&naive).unwrap().timestamp_millis() as u64
+}
+
+/// Unix millis of noon UTC on `date`, `YYYY-MM-DD`.
+pub fn noon(date: &str) -> u64 {
+    millis_in(chrono_tz::UTC, &format!("{} 12:00", date))
+}
+
+/// A Mainnet delta of account `0xabc` in transaction `0x{timestamp}`.
+pub fn delta(timestamp: u64, direction: deltas::Direction, ilk: deltas::Ilk, asset: &str, qty: f64) -> deltas::Delta {
+    deltas::Delta {
+        timestamp,
+        direction,
+        ilk,
+        asset: asset.to_string(),
+        qty,
+        host: deltas::Host::Mainnet,
+        account: "0xabc".to_string(),
+        identifier: format!("0x{}", timestamp),
+        linked_to: Vec::new(),
+        contract: None,
+    }
+}
+
+/// A lot acquired at `timestamp` in transaction `0x{timestamp}`.
+pub fn lot(timestamp: u64, qty: f64, cost: f64) -> inventory::Lot {
+    inventory::Lot { timestamp, qty, cost, host: None, account: None, identifier: Some(format!("0x{}", timestamp)), borrowed: false }
+}
+
+/// A short-term swap of `qty` ETH in transaction `identifier` on `host`,
+/// disposed of on 2025-06-01.
+pub fn disposition(identifier: &str, host: deltas::Host, qty: f64, proceeds: f64, cost_basis: f64) -> disposition::Disposition {
+    disposition::Disposition {
+        asset: "ETH".to_string(),
+        qty,
+        disposed: noon("2025-06-01"),
+        acquired: noon("2025-01-02"),
+        proceeds,
+        cost_basis,
+        long_term: false,
+        inventory_method: "FIFO".to_string(),
+        acquisition_identifier: None,
+        acquisition_host: None,
+        acquisition_account: None,
+        disposition_identifier: identifier.to_string(),
+        disposition_ilk: deltas::Ilk::Swap,
+        disposition_host: host,
+        disposition_account: "0xabc".to_string(),
+    }
+}
+
+/// Daily prices from `(asset, YYYY-MM-DD, price)` quotes.
+pub fn prices(quotes: &[(&str, &str, f64)]) -> prices::Prices {
+    let mut map: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    for (asset, date, price) in quotes {
+        map.entry(asset.to_string()).or_default().insert(date.to_string(), *price);
+    }
+    prices::Prices { map }
+}
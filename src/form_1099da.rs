@@ -0,0 +1,313 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::error::Error;
+use crate::deltas;
+use crate::disposition;
+use crate::symbols;
+use chrono::TimeZone;
+
+/// Proceeds or basis differences smaller than this are rounding.
+const AMOUNT_TOLERANCE: f64 = 0.01;
+/// Relative slack when matching quantities, for brokers that round them.
+const QTY_TOLERANCE: f64 = 0.000001;
+
+
+/// One sale as a broker reported it on Form 1099-DA.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReportedSale {
+    pub host: deltas::Host,
+    pub asset: String,
+    /// `YYYY-MM-DD`.
+    pub date_sold: String,
+    pub qty: f64,
+    pub proceeds: f64,
+    /// Reported for covered lots, which the broker knows the basis of. The
+    /// Form 8949 box follows whether it is there.
+    #[serde(default)]
+    pub cost_basis: Option<f64>,
+}
+
+impl ReportedSale {
+
+    /// Loads a broker's sales from a `.csv` file with columns
+    /// `host,asset,date_sold,quantity,proceeds,cost_basis` (basis may be
+    /// empty), or otherwise from a JSON list.
+    pub fn load_all(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        if !path.ends_with(".csv") {
+            let inner: Vec<Self> = serde_json::from_str(&data)?;
+            return Ok(inner)
+        }
+
+        let mut sales = Vec::new();
+        for (i, line) in data.lines().enumerate().skip(1) {
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if fields.len() < 5 {
+                return Err(format!("{} line {}: expected at least 5 columns", path, i + 1).into());
+            }
+            sales.push(Self {
+                host: serde_json::from_str(&format!("\"{}\"", fields[0]))?,
+                asset: fields[1].to_string(),
+                date_sold: fields[2].to_string(),
+                qty: fields[3].parse()?,
+                proceeds: fields[4].parse()?,
+                cost_basis: match fields.get(5) {
+                    Some(basis) if !basis.is_empty() => Some(basis.parse()?),
+                    _ => None,
+                },
+            });
+        }
+        Ok(sales)
+    }
+}
+
+/// One of our disposals, all its lots together, as a broker would see it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sale {
+    pub identifier: String,
+    pub ilk: deltas::Ilk,
+    pub host: deltas::Host,
+    /// Tax ticker.
+    pub asset: String,
+    /// `YYYY-MM-DD` in the holding-period time zone.
+    pub date_sold: String,
+    pub qty: f64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+}
+
+/// A reported sale and the disposal it matched, with the Form 8949
+/// adjustment codes the differences call for: `B` when the reported basis
+/// is wrong, `E` when the reported proceeds are higher than ours (fees the
+/// broker didn't net out), `B` again when they are lower.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Matched {
+    pub reported: ReportedSale,
+    pub ours: Sale,
+    pub codes: String,
+}
+
+impl Matched {
+    pub fn proceeds_difference(&self) -> f64 {
+        self.ours.proceeds - self.reported.proceeds
+    }
+
+    pub fn basis_difference(&self) -> Option<f64> {
+        self.reported.cost_basis.map(|basis| self.ours.cost_basis - basis)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Reconciliation {
+    pub matched: Vec<Matched>,
+    /// Our disposals on custodial hosts no 1099-DA reports.
+    pub not_reported: Vec<Sale>,
+    /// Reported sales we have no disposal for.
+    pub not_ours: Vec<ReportedSale>,
+}
+
+impl Reconciliation {
+
+    /// Matches each reported sale to one of our disposals on the same host,
+    /// of the same asset, on the same date and of the same quantity.
+    pub fn reconcile(dispositions: &disposition::Dispositions, reported: &[ReportedSale], time_zone: chrono_tz::Tz) -> Self {
+        let mut sales: Vec<Sale> = Vec::new();
+        let mut index: HashMap<(String, deltas::Ilk, String), usize> = HashMap::new();
+        for d in dispositions.0.iter().filter(|d| !d.is_casualty_loss()) {
+            let key = (d.disposition_identifier.clone(), d.disposition_ilk.clone(), d.asset.clone());
+            match index.get(&key) {
+                Some(&i) => {
+                    sales[i].qty += d.qty;
+                    sales[i].proceeds += d.proceeds;
+                    sales[i].cost_basis += d.cost_basis;
+                },
+                None => {
+                    index.insert(key, sales.len());
+                    sales.push(Sale {
+                        identifier: d.disposition_identifier.clone(),
+                        ilk: d.disposition_ilk.clone(),
+                        host: d.disposition_host.clone(),
+                        asset: d.asset.clone(),
+                        date_sold: time_zone.timestamp_millis_opt(d.disposed as i64).unwrap().format("%F").to_string(),
+                        qty: d.qty,
+                        proceeds: d.proceeds,
+                        cost_basis: d.cost_basis,
+                    });
+                },
+            }
+        }
+
+        let mut reconciliation = Self::default();
+        let mut used = vec![false; sales.len()];
+        for r in reported {
+            let asset = symbols::onchain_ticker_to_tax_ticker(&r.asset);
+            let found = sales.iter().enumerate().position(|(i, s)| {
+                !used[i]
+                    && s.host == r.host
+                    && s.asset == asset
+                    && s.date_sold == r.date_sold
+                    && (s.qty - r.qty).abs() <= QTY_TOLERANCE * r.qty.abs().max(1.0)
+            });
+            match found {
+                Some(i) => {
+                    used[i] = true;
+                    let mut matched = Matched { reported: r.clone(), ours: sales[i].clone(), codes: String::new() };
+                    if matched.basis_difference().is_some_and(|difference| difference.abs() > AMOUNT_TOLERANCE) {
+                        matched.codes.push('B');
+                    }
+                    let proceeds_difference = matched.proceeds_difference();
+                    if proceeds_difference < -AMOUNT_TOLERANCE {
+                        matched.codes.push('E');
+                    } else if proceeds_difference > AMOUNT_TOLERANCE && !matched.codes.contains('B') {
+                        matched.codes.push('B');
+                    }
+                    reconciliation.matched.push(matched);
+                },
+                None => reconciliation.not_ours.push(r.clone()),
+            }
+        }
+
+        reconciliation.not_reported = sales.into_iter().enumerate()
+            .filter(|(i, s)| !used[*i] && s.host.is_custodial_exchange())
+            .map(|(_, s)| s)
+            .collect();
+        reconciliation
+    }
+
+    /// The match for one of our disposals, if a broker reported it.
+    pub fn find(&self, identifier: &str, ilk: &deltas::Ilk, asset: &str) -> Option<&Matched> {
+        self.matched.iter().find(|m| m.ours.identifier == identifier && m.ours.ilk == *ilk && m.ours.asset == asset)
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let mismatched: Vec<&Matched> = self.matched.iter().filter(|m| !m.codes.is_empty()).collect();
+
+        report += &format!("1099-DA reconciliation: {} matched, {} with differences, {} reported but not ours, {} ours but not reported\n",
+            self.matched.len(), mismatched.len(), self.not_ours.len(), self.not_reported.len());
+
+        if !mismatched.is_empty() {
+            report += "\ndifferences (date, host, asset, quantity, reported proceeds, our proceeds, reported basis, our basis, codes, identifier):\n";
+            for m in mismatched {
+                report += &format!(
+                    " {} {:?} {} {:.8}: {:.2} vs {:.2}, {} vs {:.2}, {} ({})\n",
+                    m.ours.date_sold,
+                    m.ours.host,
+                    m.ours.asset,
+                    m.ours.qty,
+                    m.reported.proceeds,
+                    m.ours.proceeds,
+                    m.reported.cost_basis.map(|basis| format!("{:.2}", basis)).unwrap_or("-".to_string()),
+                    m.ours.cost_basis,
+                    m.codes,
+                    m.ours.identifier,
+                );
+            }
+        }
+        if !self.not_ours.is_empty() {
+            report += "\nreported sales with no matching disposal:\n";
+            for r in &self.not_ours {
+                report += &format!(" {} {:?} {} {:.8}: proceeds {:.2}\n", r.date_sold, r.host, r.asset, r.qty, r.proceeds);
+            }
+        }
+        if !self.not_reported.is_empty() {
+            report += "\ncustodial disposals no 1099-DA reports:\n";
+            for s in &self.not_reported {
+                report += &format!(" {} {:?} {} {:.8}: proceeds {:.2} ({})\n", s.date_sold, s.host, s.asset, s.qty, s.proceeds, s.identifier);
+            }
+        }
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::form_8949;
+
+    fn reported(asset: &str, qty: f64, proceeds: f64, cost_basis: Option<f64>) -> ReportedSale {
+        ReportedSale { host: deltas::Host::Coinbase, asset: asset.to_string(), date_sold: "2025-06-01".to_string(), qty, proceeds, cost_basis }
+    }
+
+    #[test]
+    fn matches_a_disposal_split_over_lots() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.4, 1200.0, 400.0),
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.6, 1800.0, 900.0),
+        ]);
+        let reconciliation = Reconciliation::reconcile(&dispositions, &[reported("WETH", 1.0, 3000.0, None)], chrono_tz::UTC);
+        assert_eq!(reconciliation.matched.len(), 1);
+        let matched = &reconciliation.matched[0];
+        assert_eq!((matched.ours.qty, matched.ours.proceeds, matched.ours.cost_basis), (1.0, 3000.0, 1300.0));
+        assert!(matched.codes.is_empty());
+        assert!(reconciliation.find("0xa", &deltas::Ilk::Swap, "ETH").is_some());
+        assert!(reconciliation.not_ours.is_empty() && reconciliation.not_reported.is_empty());
+    }
+
+    #[test]
+    fn codes_follow_the_differences() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xhigher", deltas::Host::Coinbase, 1.0, 3000.0, 1000.0),
+            fixtures::disposition("0xlower", deltas::Host::Coinbase, 2.0, 6000.0, 2000.0),
+            fixtures::disposition("0xbasis", deltas::Host::Coinbase, 3.0, 9000.0, 3000.0),
+        ]);
+        let sales = [
+            reported("ETH", 1.0, 3010.0, None),
+            reported("ETH", 2.0, 5990.0, None),
+            reported("ETH", 3.0, 9010.0, Some(2500.0)),
+        ];
+        let reconciliation = Reconciliation::reconcile(&dispositions, &sales, chrono_tz::UTC);
+        let codes: Vec<&str> = reconciliation.matched.iter().map(|m| m.codes.as_str()).collect();
+        assert_eq!(codes, vec!["E", "B", "BE"]);
+    }
+
+    #[test]
+    fn unmatched_sales_are_listed_on_both_sides() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xexchange", deltas::Host::Coinbase, 1.0, 3000.0, 1000.0),
+            fixtures::disposition("0xonchain", deltas::Host::Mainnet, 1.0, 3000.0, 1000.0),
+        ]);
+        let reconciliation = Reconciliation::reconcile(&dispositions, &[reported("ETH", 1.5, 4500.0, None)], chrono_tz::UTC);
+        assert!(reconciliation.matched.is_empty());
+        assert_eq!(reconciliation.not_ours.len(), 1);
+        assert_eq!(reconciliation.not_reported.iter().map(|s| s.identifier.as_str()).collect::<Vec<_>>(), vec!["0xexchange"]);
+    }
+
+    #[test]
+    fn form_8949_shows_reported_proceeds_with_an_adjustment() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.25, 750.0, 250.0),
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.75, 2250.0, 750.0),
+        ]);
+        let reconciliation = Reconciliation::reconcile(&dispositions, &[reported("ETH", 1.0, 3100.0, None)], chrono_tz::UTC);
+        let form = form_8949::Form8949::from_dispositions(2025, &dispositions, chrono_tz::UTC, Some(&reconciliation));
+        assert_eq!(form.rows.iter().map(|row| (row.proceeds, row.adjustment, row.gain)).collect::<Vec<_>>(), vec![(775.0, -25.0, 500.0), (2325.0, -75.0, 1500.0)]);
+        assert!(form.rows.iter().all(|row| row.adjustment_code == "E" && row.category == form_8949::Box8949::H));
+    }
+
+    #[test]
+    fn form_8949_shows_reported_basis_with_an_adjustment() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.25, 750.0, 200.0),
+            fixtures::disposition("0xa", deltas::Host::Coinbase, 0.75, 2250.0, 600.0),
+        ]);
+        let reconciliation = Reconciliation::reconcile(&dispositions, &[reported("ETH", 1.0, 3000.0, Some(1000.0))], chrono_tz::UTC);
+        let form = form_8949::Form8949::from_dispositions(2025, &dispositions, chrono_tz::UTC, Some(&reconciliation));
+        assert_eq!(form.rows.iter().map(|row| (row.cost_basis, row.adjustment, row.gain)).collect::<Vec<_>>(), vec![(250.0, 50.0, 550.0), (750.0, 150.0, 1650.0)]);
+        assert!(form.rows.iter().all(|row| row.adjustment_code == "B" && row.category == form_8949::Box8949::G));
+    }
+
+    #[test]
+    fn form_8949_box_follows_whether_basis_was_reported() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0xwith", deltas::Host::Coinbase, 1.0, 3000.0, 1000.0),
+            fixtures::disposition("0xwithout", deltas::Host::Coinbase, 2.0, 6000.0, 2000.0),
+        ]);
+        let sales = [reported("ETH", 1.0, 3000.0, Some(1000.0)), reported("ETH", 2.0, 6000.0, None)];
+        let reconciliation = Reconciliation::reconcile(&dispositions, &sales, chrono_tz::UTC);
+        let form = form_8949::Form8949::from_dispositions(2025, &dispositions, chrono_tz::UTC, Some(&reconciliation));
+        assert_eq!(form.rows.iter().map(|row| row.category).collect::<Vec<_>>(), vec![form_8949::Box8949::G, form_8949::Box8949::H]);
+    }
+}
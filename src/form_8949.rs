@@ -0,0 +1,252 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use crate::disposition;
+use crate::form_1099da;
+use chrono::TimeZone;
+
+
+/// The checkbox at the top of a Form 8949 part. From 2025 digital assets
+/// use their own boxes (G-L) for Form 1099-DA in place of A-F.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Box8949 {
+    /// Short term, no 1099-B.
+    C,
+    /// Long term, no 1099-B.
+    F,
+    /// Short term, 1099-DA with basis reported to the IRS.
+    G,
+    /// Short term, 1099-DA with basis not reported to the IRS.
+    H,
+    /// Short term, no 1099-DA.
+    I,
+    /// Long term, 1099-DA with basis reported to the IRS.
+    J,
+    /// Long term, 1099-DA with basis not reported to the IRS.
+    K,
+    /// Long term, no 1099-DA.
+    L,
+}
+
+impl Box8949 {
+    /// Box for a disposal in tax `year`, `reported` on a 1099-DA or not,
+    /// and if so with `basis_reported` to the IRS or not. 1099-DAs start in
+    /// 2025; before that none of our hosts issued 1099-Bs for crypto.
+    pub fn for_disposal(year: i32, reported: bool, basis_reported: bool, long_term: bool) -> Self {
+        match (year >= 2025, reported, basis_reported, long_term) {
+            (false, _, _, false) => Box8949::C,
+            (false, _, _, true) => Box8949::F,
+            (true, true, true, false) => Box8949::G,
+            (true, true, true, true) => Box8949::J,
+            (true, true, false, false) => Box8949::H,
+            (true, true, false, true) => Box8949::K,
+            (true, false, _, false) => Box8949::I,
+            (true, false, _, true) => Box8949::L,
+        }
+    }
+
+    pub fn is_long_term(&self) -> bool {
+        matches!(self, Box8949::F | Box8949::J | Box8949::K | Box8949::L)
+    }
+
+    /// The Schedule D line the box's totals go on.
+    pub fn schedule_d_line(&self) -> &'static str {
+        match self {
+            Box8949::G => "1b",
+            Box8949::C | Box8949::I => "3",
+            Box8949::H => "2",
+            Box8949::J => "8b",
+            Box8949::F | Box8949::L => "10",
+            Box8949::K => "9",
+        }
+    }
+}
+
+/// One Form 8949 row.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Row8949 {
+    pub category: Box8949,
+    /// Column (a), e.g. `0.5 ETH`.
+    pub description: String,
+    /// Column (b), `MM/DD/YYYY`.
+    pub date_acquired: String,
+    /// Column (c), `MM/DD/YYYY`.
+    pub date_sold: String,
+    /// Column (d).
+    pub proceeds: f64,
+    /// Column (e).
+    pub cost_basis: f64,
+    /// Column (f), empty when there is no adjustment.
+    pub adjustment_code: String,
+    /// Column (g).
+    pub adjustment: f64,
+    /// Column (h) = (d) - (e) + (g).
+    pub gain: f64,
+}
+
+/// Form 8949 rows for a year, with what Schedule D needs from them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Form8949 {
+    pub year: i32,
+    pub rows: Vec<Row8949>,
+}
+
+impl Form8949 {
+
+    /// One row per disposition record, leaving out theft and scam losses.
+    /// Dates are calendar dates in `time_zone`, as for the holding period.
+    ///
+    /// Without a 1099-DA `reconciliation`, disposals on custodial exchanges
+    /// are taken to be reported, without basis. With one, only matched
+    /// disposals are, with basis when the broker's sale shows it. Where the
+    /// broker's figures differ from ours, columns (d) and (e) show the
+    /// broker's proceeds and any basis it reported (split over the lots like
+    /// ours), column (f) the reconciliation's codes and column (g) the
+    /// difference, so the gain is unchanged.
+    pub fn from_dispositions(year: i32, dispositions: &disposition::Dispositions, time_zone: chrono_tz::Tz, reconciliation: Option<&form_1099da::Reconciliation>) -> Self {
+        let date = |millis: u64| time_zone.timestamp_millis_opt(millis as i64).unwrap().format("%m/%d/%Y").to_string();
+
+        let rows = dispositions.0.iter().filter(|d| !d.is_casualty_loss()).map(|d| {
+            let matched = reconciliation.and_then(|r| r.find(&d.disposition_identifier, &d.disposition_ilk, &d.asset));
+            let reported = match reconciliation {
+                Some(_) => matched.is_some(),
+                None => d.disposition_host.is_custodial_exchange(),
+            };
+            let basis_reported = matched.is_some_and(|m| m.reported.cost_basis.is_some());
+            let (proceeds, cost_basis, adjustment_code) = match matched {
+                Some(m) if !m.codes.is_empty() => {
+                    let share = if m.ours.proceeds != 0.0 { d.proceeds / m.ours.proceeds } else { d.qty / m.ours.qty };
+                    let cost_basis = match m.reported.cost_basis {
+                        Some(basis) => basis * if m.ours.cost_basis != 0.0 { d.cost_basis / m.ours.cost_basis } else { d.qty / m.ours.qty },
+                        None => d.cost_basis,
+                    };
+                    (m.reported.proceeds * share, cost_basis, m.codes.clone())
+                },
+                _ => (d.proceeds, d.cost_basis, String::new()),
+            };
+            Row8949 {
+                category: Box8949::for_disposal(year, reported, basis_reported, d.long_term),
+                description: format!("{:.8} {}", d.qty, d.asset),
+                date_acquired: date(d.acquired),
+                date_sold: date(d.disposed),
+                proceeds,
+                cost_basis,
+                adjustment_code,
+                adjustment: d.gain() - (proceeds - cost_basis),
+                gain: d.gain(),
+            }
+        }).collect();
+        Self { year, rows }
+    }
+
+    /// Per-box totals of proceeds, basis, adjustments and gain.
+    pub fn totals(&self) -> Vec<(Box8949, [f64; 4])> {
+        let mut totals: HashMap<Box8949, [f64; 4]> = HashMap::new();
+        for row in &self.rows {
+            let t = totals.entry(row.category).or_default();
+            t[0] += row.proceeds;
+            t[1] += row.cost_basis;
+            t[2] += row.adjustment;
+            t[3] += row.gain;
+        }
+        let mut totals: Vec<(Box8949, [f64; 4])> = totals.into_iter().collect();
+        totals.sort_by_key(|(category, _)| *category);
+        totals
+    }
+
+    /// The rows as a CSV for import into tax software, short-term part first.
+    pub fn to_csv(&self) -> String {
+        let mut rows: Vec<&Row8949> = self.rows.iter().collect();
+        rows.sort_by_key(|row| (row.category.is_long_term(), row.category));
+
+        let mut csv = "part,box,description,date_acquired,date_sold,proceeds,cost_basis,adjustment_code,adjustment,gain\n".to_string();
+        for row in rows {
+            csv += &format!(
+                "{},{:?},{},{},{},{:.2},{:.2},{},{:.2},{:.2}\n",
+                if row.category.is_long_term() { "II" } else { "I" },
+                row.category,
+                row.description,
+                row.date_acquired,
+                row.date_sold,
+                row.proceeds,
+                row.cost_basis,
+                row.adjustment_code,
+                row.adjustment,
+                row.gain,
+            );
+        }
+        csv
+    }
+
+    /// Schedule D lines 1b-3 and 8b-10 from the box totals, and the net
+    /// short- and long-term gains on lines 7 and 15 before carryovers.
+    pub fn schedule_d(&self) -> String {
+        let totals = self.totals();
+        let mut report = format!("{} Schedule D, from Form 8949\n\n", self.year);
+        report += &format!("{:<6}{:<6}{:>18}{:>18}{:>18}{:>18}\n", "line", "box", "(d) proceeds", "(e) cost", "(g) adjustments", "(h) gain");
+
+        let mut net = [0.0, 0.0];
+        for (long_term, part) in [(false, "Part I, short term"), (true, "Part II, long term")] {
+            report += &format!("{}\n", part);
+            for (category, t) in totals.iter().filter(|(category, _)| category.is_long_term() == long_term) {
+                report += &format!(
+                    "{:<6}{:<6}{:>18.2}{:>18.2}{:>18.2}{:>18.2}\n",
+                    category.schedule_d_line(),
+                    format!("{:?}", category),
+                    t[0],
+                    t[1],
+                    t[2],
+                    t[3],
+                );
+                net[long_term as usize] += t[3];
+            }
+        }
+        report += "\n";
+        report += &format!("line 7, net short-term gain (before carryovers): {:.2}\n", net[0]);
+        report += &format!("line 15, net long-term gain (before carryovers): {:.2}\n", net[1]);
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deltas;
+    use crate::fixtures;
+
+    #[test]
+    fn box_depends_on_year_reporting_and_term() {
+        assert_eq!(Box8949::for_disposal(2024, true, true, false), Box8949::C);
+        assert_eq!(Box8949::for_disposal(2024, false, false, true), Box8949::F);
+        assert_eq!(Box8949::for_disposal(2025, true, false, false), Box8949::H);
+        assert_eq!(Box8949::for_disposal(2025, true, false, true), Box8949::K);
+        assert_eq!(Box8949::for_disposal(2026, true, true, false), Box8949::G);
+        assert_eq!(Box8949::for_disposal(2026, true, true, true), Box8949::J);
+        assert_eq!(Box8949::for_disposal(2025, false, false, false), Box8949::I);
+        assert_eq!(Box8949::for_disposal(2026, false, false, true), Box8949::L);
+        assert_eq!(Box8949::G.schedule_d_line(), "1b");
+        assert_eq!(Box8949::H.schedule_d_line(), "2");
+        assert_eq!(Box8949::J.schedule_d_line(), "8b");
+        assert_eq!(Box8949::K.schedule_d_line(), "9");
+        assert!(!Box8949::I.is_long_term());
+        assert!(Box8949::J.is_long_term());
+    }
+
+    #[test]
+    fn rows_and_totals_leave_out_casualty_losses() {
+        let dispositions = disposition::Dispositions(vec![
+            fixtures::disposition("0x1", deltas::Host::Coinbase, 1.0, 3000.0, 1000.0),
+            fixtures::disposition("0x2", deltas::Host::Mainnet, 1.0, 2000.0, 1500.0),
+            disposition::Disposition { long_term: true, acquired: fixtures::noon("2023-01-02"), ..fixtures::disposition("0x3", deltas::Host::Mainnet, 2.0, 500.0, 800.0) },
+            disposition::Disposition { disposition_ilk: deltas::Ilk::Loss, ..fixtures::disposition("0x4", deltas::Host::Mainnet, 1.0, 0.0, 1200.0) },
+        ]);
+        let form = Form8949::from_dispositions(2025, &dispositions, chrono_tz::UTC, None);
+        assert_eq!(form.rows.iter().map(|row| row.category).collect::<Vec<_>>(), vec![Box8949::H, Box8949::I, Box8949::L]);
+        assert_eq!((form.rows[2].description.as_str(), form.rows[2].date_acquired.as_str(), form.rows[2].date_sold.as_str()), ("2.00000000 ETH", "01/02/2023", "06/01/2025"));
+        assert_eq!(form.totals(), vec![
+            (Box8949::H, [3000.0, 1000.0, 0.0, 2000.0]),
+            (Box8949::I, [2000.0, 1500.0, 0.0, 500.0]),
+            (Box8949::L, [500.0, 800.0, 0.0, -300.0]),
+        ]);
+    }
+}
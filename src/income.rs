@@ -0,0 +1,124 @@
+use serde::{Serialize, Deserialize};
+use crate::deltas;
+
+
+/// Kinds of ordinary income reported separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IncomeCategory {
+    Airdrop,
+    Staking,
+    Interest,
+    Rewards,
+    /// A liquidity provider's share of trading fees.
+    LiquidityFees,
+    FeeRebate,
+}
+
+/// Which Ins are income, by `Ilk`. An income In is recognized at its fair
+/// market value, which also becomes the basis of the lot it creates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IncomeTable ( pub Vec<(deltas::Ilk, IncomeCategory)> );
+
+impl Default for IncomeTable {
+    fn default() -> Self {
+        Self(vec![
+            (deltas::Ilk::Airdrop, IncomeCategory::Airdrop),
+            (deltas::Ilk::StakingYield, IncomeCategory::Staking),
+            (deltas::Ilk::CoinbaseInterest, IncomeCategory::Interest),
+            (deltas::Ilk::Reward, IncomeCategory::Rewards),
+            (deltas::Ilk::SwapFees, IncomeCategory::LiquidityFees),
+            (deltas::Ilk::TradeFee, IncomeCategory::FeeRebate),
+        ])
+    }
+}
+
+impl IncomeTable {
+    /// The category of `delta` if it is income.
+    pub fn category(&self, delta: &deltas::Delta) -> Option<IncomeCategory> {
+        if delta.direction != deltas::Direction::In {
+            return None
+        }
+        self.0.iter().find(|(ilk, _)| *ilk == delta.ilk).map(|(_, category)| *category)
+    }
+}
+
+/// Income for the year split by category.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IncomeBreakdown {
+    pub airdrop: f64,
+    pub staking: f64,
+    pub interest: f64,
+    pub rewards: f64,
+    #[serde(default)]
+    pub liquidity_fees: f64,
+    pub fee_rebates: f64,
+}
+
+impl IncomeBreakdown {
+
+    pub fn add(&mut self, category: IncomeCategory, amount: f64) {
+        match category {
+            IncomeCategory::Airdrop => self.airdrop += amount,
+            IncomeCategory::Staking => self.staking += amount,
+            IncomeCategory::Interest => self.interest += amount,
+            IncomeCategory::Rewards => self.rewards += amount,
+            IncomeCategory::LiquidityFees => self.liquidity_fees += amount,
+            IncomeCategory::FeeRebate => self.fee_rebates += amount,
+        }
+    }
+
+    /// Report labels and amounts, in report order.
+    pub fn lines(&self) -> [(&'static str, f64); 6] {
+        [
+            ("airdrops", self.airdrop),
+            ("staking", self.staking),
+            ("interest", self.interest),
+            ("rewards", self.rewards),
+            ("liquidity fees", self.liquidity_fees),
+            ("fee rebates", self.fee_rebates),
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn delta(direction: deltas::Direction, ilk: deltas::Ilk) -> deltas::Delta {
+        fixtures::delta(fixtures::noon(fixtures::DAY), direction, ilk, "ETH", 1.0)
+    }
+
+    #[test]
+    fn default_table_keeps_liquidity_fees_apart_from_rewards() {
+        let table = IncomeTable::default();
+        assert_eq!(table.category(&delta(deltas::Direction::In, deltas::Ilk::SwapFees)), Some(IncomeCategory::LiquidityFees));
+        assert_eq!(table.category(&delta(deltas::Direction::In, deltas::Ilk::Reward)), Some(IncomeCategory::Rewards));
+        assert_eq!(table.category(&delta(deltas::Direction::In, deltas::Ilk::Airdrop)), Some(IncomeCategory::Airdrop));
+    }
+
+    #[test]
+    fn outs_and_unlisted_ilks_are_not_income() {
+        let table = IncomeTable::default();
+        assert_eq!(table.category(&delta(deltas::Direction::Out, deltas::Ilk::Reward)), None);
+        assert_eq!(table.category(&delta(deltas::Direction::In, deltas::Ilk::Swap)), None);
+    }
+
+    #[test]
+    fn breakdown_totals_by_category() {
+        let mut breakdown = IncomeBreakdown::default();
+        breakdown.add(IncomeCategory::Rewards, 10.0);
+        breakdown.add(IncomeCategory::LiquidityFees, 4.0);
+        breakdown.add(IncomeCategory::LiquidityFees, 1.5);
+        breakdown.add(IncomeCategory::FeeRebate, 2.0);
+        assert_eq!(breakdown.lines(), [
+            ("airdrops", 0.0),
+            ("staking", 0.0),
+            ("interest", 0.0),
+            ("rewards", 10.0),
+            ("liquidity fees", 5.5),
+            ("fee rebates", 2.0),
+        ]);
+    }
+}
@@ -4,20 +4,65 @@ use std::error::Error;
 use crate::symbols;
 use crate::deltas;
 use crate::prices;
-use chrono::{Utc, TimeZone};
-
-const MILLIS_YEAR: u64 = 31557600000;
-
-#[derive(Serialize)]
+use crate::specific_id;
+use crate::shortfall;
+use crate::disposition;
+use crate::error;
+use crate::fees;
+use crate::income;
+use crate::loans;
+use crate::migrations;
+use crate::position_assets;
+use crate::positions;
+use chrono::{Utc, TimeZone, Months};
+
+#[derive(Serialize, Deserialize)]
 pub struct CapitalGainsSummary {
     pub inventory_method: String,
     pub income: f64,
+    #[serde(default)]
+    pub income_by_category: income::IncomeBreakdown,
+    #[serde(default)]
+    pub fees: fees::FeeTotals,
+    /// Basis of lots lost to theft and scams (`Ilk::Loss`), kept out of
+    /// the capital gains.
+    #[serde(default)]
+    pub casualty_losses: f64,
+    /// Loan interest paid, at fair market value.
+    #[serde(default)]
+    pub loan_interest: f64,
     pub short_term_capital_gains: f64,
     pub long_term_capital_gains: f64,
 }
 
+/// What `Inventory::apply_deltas` produces besides the end inventory.
+pub struct Calculation {
+    pub summary: CapitalGainsSummary,
+    pub dispositions: disposition::Dispositions,
+    /// The lots every fully covered disposal consumed, as a selection file
+    /// that reproduces the run.
+    pub selections: specific_id::LotSelections,
+    /// Every disposal or transfer of more than was held, treated per
+    /// `Rules::shortfall_policy`.
+    pub shortfalls: Vec<shortfall::Shortfall>,
+    pub loans: loans::LoanLedger,
+    /// Concentrated-liquidity positions added to or removed from.
+    pub positions: positions::Positions,
+    /// Migration and rename deltas `Rules::migrations` doesn't cover.
+    pub unmatched_migrations: Vec<migrations::Unmatched>,
+    /// Shortfalls still uncovered at the end of the year, for the next
+    /// year's acquisitions to make good.
+    pub owed: Vec<shortfall::Owed>,
+}
+
 impl CapitalGainsSummary {
 
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Self = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
     pub fn save (&self, path: &str) -> Result<(), Box<dyn Error>> {
         let json_string = serde_json::to_string(&self)?;
         std::fs::write(path, &json_string)?;
@@ -31,13 +76,23 @@ pub struct Lot {
     pub qty: f64,
     pub cost: f64,
     pub host: Option<deltas::Host>,
+    /// Account on `host` the lot was acquired in.
+    #[serde(default)]
+    pub account: Option<String>,
     pub identifier: Option<String>,
+    /// Brought in by a loan, and the first lot its repayment takes back.
+    #[serde(default)]
+    pub borrowed: bool,
 }
 
 impl Lot {
-    pub fn remove_qty(&mut self, qty: f64) -> Self {
+    /// Splits `qty` off the lot, which keeps the rest. Taking all of it
+    /// or more is a `Lots` error against `asset`; take the whole lot then.
+    pub fn remove_qty(&mut self, qty: f64, asset: &str) -> Result<Self, error::CalcError> {
 
-        assert!(qty < self.qty);
+        if qty >= self.qty {
+            return Err(error::CalcError::Lots { asset: asset.to_string(), reason: format!("can't split {} off a lot of {}", qty, self.qty) });
+        }
 
 
         // let price = cost/self.qty;
@@ -46,13 +101,15 @@ impl Lot {
         self.qty -= qty;
         self.cost -= removed_cost;
 
-        Self {
+        Ok(Self {
             timestamp: self.timestamp,
             qty: qty,
             cost: removed_cost,
             host: self.host.clone(),
+            account: self.account.clone(),
             identifier: self.identifier.clone(),
-        }
+            borrowed: self.borrowed,
+        })
     }
 }
 
@@ -61,11 +118,185 @@ impl Lot {
 pub struct Inventory ( pub HashMap<String, Vec<Lot>> );
 
 
-#[derive(Clone, Copy)]
+/// Whether lots are pooled across every wallet holding an asset, or kept
+/// separately per wallet (host and account) as US rules require from 2025.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LotScope {
+    #[default]
+    Pooled,
+    PerWallet,
+}
+
+/// Treatment choices `Inventory::apply_deltas` makes independent of the
+/// inventory method.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub lot_scope: LotScope,
+    #[serde(default)]
+    pub holding_period: HoldingPeriod,
+    #[serde(default)]
+    pub shortfall_policy: shortfall::ShortfallPolicy,
+    #[serde(default)]
+    pub income: income::IncomeTable,
+    #[serde(default)]
+    pub fees: fees::FeePolicy,
+    #[serde(default)]
+    pub migrations: migrations::MigrationTable,
+}
+
+impl Rules {
+    /// The inventory key lots for `delta` live under.
+    pub fn lot_key(&self, delta: &deltas::Delta) -> String {
+        match self.lot_scope {
+            LotScope::Pooled => symbols::delta_tax_ticker(delta),
+            LotScope::PerWallet => wallet_key(&symbols::delta_tax_ticker(delta), &delta.host, &delta.account),
+        }
+    }
+}
+
+/// Inventory key for `ticker` held in one wallet: `{ticker}@{host}:{account}`.
+pub fn wallet_key(ticker: &str, host: &deltas::Host, account: &str) -> String {
+    format!("{}@{:?}:{}", ticker, host, account)
+}
+
+/// The tax ticker an inventory key holds, whether pooled or per wallet.
+pub fn key_ticker(key: &str) -> &str {
+    match key.split_once('@') {
+        Some((ticker, _)) => ticker,
+        None => key,
+    }
+}
+
+/// Whether an inventory key holds lots kept out of the wallets: spam, or
+/// collateral posted for a loan. Wallet balances don't count them.
+pub fn is_held_apart(key: &str) -> bool {
+    deltas::is_spam_id(key) || loans::is_collateral_key(key)
+}
+
+/// Marginal rates the tax-minimizing method weighs gains and losses by.
+/// Loss rates are the value of a dollar of loss, so they are positive too.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TaxRates {
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub short_term_loss: f64,
+    pub long_term_loss: f64,
+}
+
+impl Default for TaxRates {
+    fn default() -> Self {
+        Self {
+            short_term_gain: 0.37,
+            long_term_gain: 0.20,
+            short_term_loss: 0.37,
+            long_term_loss: 0.20,
+        }
+    }
+}
+
+impl TaxRates {
+    /// Tax owed on `gain` (negative when it is a loss).
+    pub fn tax(&self, gain: f64, long_term: bool) -> f64 {
+        let rate = match (gain >= 0.0, long_term) {
+            (true, false) => self.short_term_gain,
+            (true, true) => self.long_term_gain,
+            (false, false) => self.short_term_loss,
+            (false, true) => self.long_term_loss,
+        };
+        gain * rate
+    }
+}
+
+/// Decides long vs short term the way the IRS counts "more than one
+/// year": by calendar date in the taxpayer's time zone. The day after the
+/// acquisition date one year on is the first long-term day; a lot acquired
+/// on Feb 29 has its anniversary on Feb 28.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct HoldingPeriod {
+    pub time_zone: chrono_tz::Tz,
+}
+
+impl Default for HoldingPeriod {
+    fn default() -> Self {
+        Self { time_zone: chrono_tz::UTC }
+    }
+}
+
+impl HoldingPeriod {
+    /// Whether a lot acquired at `acquired` and disposed of at `disposed`
+    /// (both unix millis) was held long term.
+    pub fn is_long_term(&self, acquired: u64, disposed: u64) -> bool {
+        let acquired = self.date(acquired);
+        let anniversary = acquired.checked_add_months(Months::new(12)).unwrap();
+        self.date(disposed) > anniversary
+    }
+
+    /// The calendar date of `millis` in the taxpayer's time zone.
+    pub fn date(&self, millis: u64) -> chrono::NaiveDate {
+        self.time_zone.timestamp_millis_opt(millis as i64).unwrap().date_naive()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum InventoryMethod {
     Fifo,
     Lifo,
+    /// Oldest lot if it is already long term, otherwise the newest.
     Yipo,
+    Hifo,
+    /// Lots named in a `specific_id::LotSelections` file, falling back to
+    /// the file's default method for disposals it doesn't cover.
+    SpecificId,
+    /// For each disposal, the lots that add the least tax at these rates.
+    /// Greedy per disposal: it doesn't look ahead to later disposals, so
+    /// the year as a whole isn't guaranteed its minimum. A lot this
+    /// disposal spares may be taken later at a worse rate.
+    MinTax(TaxRates),
+}
+
+impl InventoryMethod {
+    /// The methods that need nothing but the inventory itself to pick lots.
+    pub const ALL: [InventoryMethod; 4] = [InventoryMethod::Fifo, InventoryMethod::Lifo, InventoryMethod::Yipo, InventoryMethod::Hifo];
+
+    /// The method wallet transfers move lots by. Transfers have no
+    /// selections and no proceeds to optimize, so the methods that need
+    /// either move lots first in, first out.
+    fn for_transfers(self) -> Self {
+        match self {
+            InventoryMethod::SpecificId | InventoryMethod::MinTax(_) => InventoryMethod::Fifo,
+            _ => self,
+        }
+    }
+
+    /// Name used for the method in summaries and reports.
+    pub fn label(&self) -> String {
+        match self {
+            InventoryMethod::Fifo => "FIFO".to_string(),
+            InventoryMethod::Lifo => "LIFO".to_string(),
+            InventoryMethod::Yipo => "YIPO".to_string(),
+            InventoryMethod::Hifo => "HIFO".to_string(),
+            InventoryMethod::SpecificId => "Specific_Id".to_string(),
+            InventoryMethod::MinTax(_) => "Greedy_Min_Tax".to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for InventoryMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" => Ok(InventoryMethod::Fifo),
+            "lifo" => Ok(InventoryMethod::Lifo),
+            "yipo" => Ok(InventoryMethod::Yipo),
+            "hifo" => Ok(InventoryMethod::Hifo),
+            "specific" | "specific_id" => Ok(InventoryMethod::SpecificId),
+            "mintax" | "min_tax" => Ok(InventoryMethod::MinTax(TaxRates::default())),
+            _ => Err(format!("unknown inventory method: {} (expected fifo, lifo, yipo, hifo, specific or mintax)", s)),
+        }
+    }
 }
 
 
@@ -80,10 +311,10 @@ impl Inventory {
                 qty: *balance,
                 cost: 0_f64,
                 host: None,
+                account: None,
                 identifier: None,
-
+                borrowed: false,
             };
-            assert!(!lots_inner.contains_key(asset));
             lots_inner.insert(asset.clone(), vec![acq]);
         }
         Self ( lots_inner )
@@ -108,233 +339,403 @@ impl Inventory {
     }
 
 
+    /// Total cost of the lots held for each asset.
+    pub fn cost_basis(&self) -> HashMap<String, f64> {
+        self.0.iter()
+            .map(|(asset, lots)| (asset.clone(), lots.iter().map(|lot| lot.cost).sum()))
+            .collect()
+    }
+
+    /// Moves the lots held under `alias` to `name`. Nothing held under
+    /// `alias` leaves the inventory as it was.
     pub fn consolidate_alias(&mut self, name: &str, alias: &str) {
 
-        let mut to_copy = self.0[alias].clone();
-        self.0.get_mut(name).unwrap().append(&mut to_copy);
-        self.0.get_mut(name).unwrap().sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        self.0.remove(alias);
+        if let Some(mut to_copy) = self.0.remove(alias) {
+            let lots = self.0.entry(name.to_string()).or_default();
+            lots.append(&mut to_copy);
+            lots.sort_by_key(|lot| lot.timestamp);
+        }
 
     }
 
-    pub fn apply_deltas(&mut self, linked_deltas: &deltas::LinkedDeltas, quote_currency: &str, prices: &prices::Prices, method: InventoryMethod) -> (CapitalGainsSummary, String) {
+    /// `selections` is required for `InventoryMethod::SpecificId` and
+    /// ignored otherwise; an entry no disposal or repayment uses is an
+    /// error. `owed` is what last year's shortfalls left for this year's
+    /// acquisitions to make good. `rules` holds the treatment choices that don't
+    /// depend on the method, such as whether lots are pooled. Borrowing,
+    /// repaying and posting collateral move lots without gains, and a
+    /// removal that closes a concentrated-liquidity position takes all
+    /// that is left of it. A delta that can't be processed is skipped and
+    /// the run carries on, so the error returned lists every such delta.
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply_deltas(&mut self, linked_deltas: &deltas::LinkedDeltas, quote_currency: &str, prices: &prices::Prices, method: InventoryMethod, selections: Option<&specific_id::LotSelections>, owed: &[shortfall::Owed], rules: &Rules) -> Result<Calculation, error::CalcErrors> {
+
+        let mut errors = Vec::new();
+        for (key, lots) in &self.0 {
+            if lots.iter().any(|lot| lot.qty < 0.0) {
+                errors.push(error::CalcError::NegativeOpeningLot { asset: key.clone() });
+            }
+        }
 
-        let smallest_liquidity_deltas = smallet_by_pair(linked_deltas);
+        let (selections, fallback_method) = match method {
+            InventoryMethod::SpecificId => {
+                let selections = selections.ok_or_else(|| error::CalcError::Setup { reason: "specific identification needs a lot selection file".to_string() })?;
+                if selections.default_method == InventoryMethod::SpecificId {
+                    return Err(error::CalcError::Setup { reason: "lot selection default method can't itself be specific identification".to_string() }.into());
+                }
+                (Some(selections), selections.default_method)
+            },
+            _ => (None, method),
+        };
+        let mut used_selections = std::collections::HashSet::new();
+        // Every disposal and repayment that finds its lots is recorded, so
+        // replaying the file only falls back for transfers, which must move
+        // lots the way they moved here.
+        let mut made_selections = specific_id::LotSelections {
+            default_method: fallback_method.for_transfers(),
+            selections: Vec::new(),
+        };
 
+        let mut shortfalls = Vec::new();
+        let mut pending: HashMap<String, Vec<PendingShortfall>> = HashMap::new();
+        for o in owed {
+            shortfalls.push(shortfall::Shortfall::new(&o.asset, &o.delta, o.qty));
+            pending.entry(rules.lot_key(&o.delta)).or_default().push(PendingShortfall {
+                index: shortfalls.len() - 1,
+                symbol: o.asset.clone(),
+                delta: o.delta.clone(),
+                revenue: 0.0,
+                qty: o.qty,
+                carried: true,
+                to: None,
+            });
+        }
 
+        let mut positions = positions::Positions::default();
+        let mut unmatched_migrations = Vec::new();
         let mut link_only_short_term = 0.0;
         let mut link_only_long_term = 0.0;
 
-        let mut events = "asset,quantity,disposition_date,acquisition_date,proceeds_USD,cost_basis_USD,capital_gain_USD,term\n".to_string();
+        let mut events = disposition::Dispositions::default();
         let mut test_gain = 0_f64;
         let mut long_term_capital_gains = 0_f64;
         let mut short_term_capital_gains = 0_f64;
         let mut income = 0_f64;
+        let mut income_by_category = income::IncomeBreakdown::default();
+        let mut fee_totals = fees::FeeTotals::default();
+        let mut casualty_losses = 0_f64;
+        let mut ledger = loans::LoanLedger::default();
 
         let mut lowest_gain = 0f64;
 
-        for group in &linked_deltas.0 {
+        for (group_index, group) in linked_deltas.0.iter().enumerate() {
+            // Moves between our own wallets only matter when lots are
+            // tracked per wallet; pooled, they net to nothing.
+            if rules.lot_scope == LotScope::PerWallet {
+                match self.transfer_lots(group, fallback_method, rules, &mut pending, &mut shortfalls) {
+                    Ok(settled) => for settlement in settled {
+                        settlement.record(quote_currency, method, &mut short_term_capital_gains, &mut casualty_losses, &mut events);
+                    },
+                    Err((err, delta)) => errors.push(err.at(group_index, delta)),
+                }
+            }
+
+            match self.migrate_lots(group, rules) {
+                Ok((migration_shortfalls, unmatched)) => {
+                    shortfalls.extend(migration_shortfalls);
+                    unmatched_migrations.extend(unmatched);
+                },
+                Err((err, delta)) => errors.push(err.at(group_index, delta)),
+            }
+
             // Process all Ins in the group
             for delta in &group.ins {
-                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration {
+                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || migrations::is_migration(&delta.ilk) || delta.ilk == deltas::Ilk::WalletTransfer {
                     continue
                 }
 
-                let symbol = symbols::delta_tax_ticker(&delta);
+                // Tokens phishing attempts drop on us are kept apart from
+                // real tokens sharing their symbol.
+                let symbol = if delta.ilk == deltas::Ilk::PhishingAttempt || self.0.contains_key(&delta.spam_id()) {
+                    delta.spam_id()
+                } else {
+                    rules.lot_key(delta)
+                };
 
-                income += group.income_for(delta, quote_currency, &prices);
+                // Collateral coming back was never disposed of; its lots
+                // return from where they were held, oldest first.
+                if loans::role(delta) == Some(loans::LoanRole::ReturnCollateral) {
+                    let returned = self.take_collateral(&loans::collateral_key(delta), delta, InventoryMethod::Fifo, rules)
+                        .and_then(|(lots, short)| Ok((lots, short, delta.value(quote_currency, prices)?)));
+                    let (lots, short, value) = match returned {
+                        Ok(returned) => returned,
+                        Err(err) => {
+                            errors.push(err.at(group_index, delta));
+                            continue
+                        },
+                    };
+                    shortfalls.extend(short);
+                    ledger.0.push(loans::LoanEntry::new(loans::LoanRole::ReturnCollateral, delta, value, lots.iter().map(|lot| lot.cost).sum()));
+                    for lot in lots {
+                        match self.settle(&symbol, lot, delta.timestamp, &mut pending, &mut shortfalls) {
+                            Ok(settled) => for settlement in settled {
+                                settlement.record(quote_currency, method, &mut short_term_capital_gains, &mut casualty_losses, &mut events);
+                            },
+                            Err(err) => errors.push(err.at(group_index, delta)),
+                        }
+                    }
+                    continue
+                }
 
-                let cost = group.cost_for(delta, quote_currency, &prices);
+                let valued = group.income_for(delta, quote_currency, prices, rules)
+                    .and_then(|income| Ok((income, group.cost_for(delta, quote_currency, prices, rules)?)));
+                let cost = match valued {
+                    Ok((_, cost)) if cost < 0.0 => {
+                        errors.push(error::CalcError::UnexpectedDelta { asset: delta.asset.clone(), reason: format!("negative cost {}", cost) }.at(group_index, delta));
+                        continue
+                    },
+                    Ok((delta_income, cost)) => {
+                        if let Some((category, amount)) = delta_income {
+                            income += amount;
+                            income_by_category.add(category, amount);
+                        }
+                        cost
+                    },
+                    Err(err) => {
+                        errors.push(err.at(group_index, delta));
+                        continue
+                    },
+                };
 
-                if cost < 0.0 {
-                    panic!("");
+                if loans::role(delta) == Some(loans::LoanRole::Borrow) {
+                    ledger.0.push(loans::LoanEntry::new(loans::LoanRole::Borrow, delta, cost, 0.0));
                 }
 
                 if !self.0.contains_key(&symbol) {
                     self.0.insert(symbol.clone(), vec![]);
                 }
-                if self.0[&symbol].len() == 1 && self.0[&symbol][0].qty < 0.0 {
-
-                    assert!(self.0[&symbol][0].qty.abs() < delta.qty);
-                    assert!(self.0[&symbol][0].timestamp == 0);
-                    assert!(self.0[&symbol][0].cost == 0.0);
-
-                    self.0.get_mut(&symbol).unwrap()[0] = Lot {
-                        timestamp: delta.timestamp,
-                        qty: self.0[&symbol][0].qty + delta.qty,
-                        cost: cost,
-                        host: Some(delta.host.clone()),
-                        identifier: Some(delta.identifier.clone()),
-                    }
-
-                } else {
-                    let acq = Lot {
-                        timestamp: delta.timestamp,
-                        qty: delta.qty,
-                        cost: cost,
-                        host: Some(delta.host.clone()),
-                        identifier: Some(delta.identifier.clone()),
-                    };
-                    self.0.get_mut(&symbol).unwrap().push(acq);
+                if position_assets::is_position(&delta.asset) {
+                    positions.add(&symbol, delta, self.0[&symbol].iter().map(|lot| lot.qty).sum());
+                }
+                let acq = Lot {
+                    timestamp: delta.timestamp,
+                    qty: delta.qty,
+                    cost,
+                    host: Some(delta.host.clone()),
+                    account: Some(delta.account.clone()),
+                    identifier: Some(delta.identifier.clone()),
+                    borrowed: loans::role(delta) == Some(loans::LoanRole::Borrow),
                 };
+
+                // Earlier shortfalls waiting for a later acquisition take
+                // their basis from this one first.
+                match self.settle(&symbol, acq, delta.timestamp, &mut pending, &mut shortfalls) {
+                    Ok(settled) => for settlement in settled {
+                        settlement.record(quote_currency, method, &mut short_term_capital_gains, &mut casualty_losses, &mut events);
+                    },
+                    Err(err) => errors.push(err.at(group_index, delta)),
+                }
             }
 
             // Process all Outs in the group
             for delta in &group.outs {
-                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration {
+                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || migrations::is_migration(&delta.ilk) || delta.ilk == deltas::Ilk::WalletTransfer {
                     continue
                 }
 
-                let symbol = symbols::delta_tax_ticker(&delta);
-                let total_revenue = group.revenue_for(delta, quote_currency, &prices);
-
-                let mut rem_qty = delta.qty;
-                let mut removed_lots = Vec::new();
-
-                match method {
-                    InventoryMethod::Fifo => {
-
-
-                        while rem_qty > 0.0 {
-                            let symbol = symbols::delta_tax_ticker(&delta);
-                            if self.0[&symbol].len() == 0 {
-                                self.0.get_mut(&symbol).unwrap().push ( Lot {
-                                    timestamp: 0,
-                                    qty: -rem_qty,
-                                    cost: 0.0,
-                                    host: None,
-                                    identifier: None,
-                                });
-                                rem_qty = 0.0
-
-                            } else if rem_qty >= self.0[&symbol][0].qty {
-                                let removed = self.0.get_mut(&symbol).unwrap().remove(0);
-                                rem_qty -= removed.qty;
-                                removed_lots.push(removed);
-
-                            } else {
-                                let removed = self.0.get_mut(&symbol).unwrap()[0].remove_qty(rem_qty);
-                                rem_qty -= removed.qty;
-                                assert!(rem_qty == 0.0);
-                                removed_lots.push(removed);
-                            }
-                        }
+                // Spam tokens, whichever year they came in, have no market
+                // and go for nothing.
+                let spam = self.0.contains_key(&delta.spam_id());
+                let (symbol, key) = if spam {
+                    (delta.spam_id(), delta.spam_id())
+                } else {
+                    (symbols::delta_tax_ticker(delta), rules.lot_key(delta))
+                };
+                let total_revenue = if spam {
+                    Ok(0.0)
+                } else {
+                    group.revenue_for(delta, quote_currency, prices, rules)
+                };
+                let total_revenue = match total_revenue {
+                    Ok(revenue) => revenue,
+                    Err(err) => {
+                        errors.push(err.at(group_index, delta));
+                        continue
                     },
-                    InventoryMethod::Lifo => {
-                        let symbol = symbols::delta_tax_ticker(&delta);
-                        while rem_qty > 0.0 {
-
-                            if self.0[&symbol].len() == 0 {
-
-                                self.0.get_mut(&symbol).unwrap().push ( Lot {
-                                    timestamp: 0,
-                                    qty: -rem_qty,
-                                    cost: 0.0,
-                                    host: None,
-                                    identifier: None,
-
-                                });
-                                rem_qty = 0.0
-
-                            } else if rem_qty >= self.0[&symbol].last().unwrap().qty {
-                                let removed = self.0.get_mut(&symbol).unwrap().pop().unwrap();
-                                rem_qty -= removed.qty;
-                                removed_lots.push(removed);
-                            } else {
-                                let last_index = self.0[&symbol].len() - 1;
-                                let removed = self.0.get_mut(&symbol).unwrap()[ last_index ].remove_qty(rem_qty);
-                                rem_qty -= removed.qty;
-                                assert!(rem_qty == 0.0);
-                                removed_lots.push(removed);
-                            }
-                        }
+                };
+                if delta.ilk.is_fee() {
+                    let value = if delta.asset == quote_currency { delta.qty } else { total_revenue };
+                    fee_totals.add(group.fee_treatment(delta, quote_currency, &rules.fees), value);
+                }
+
+                // Posted collateral moves, basis and dates intact, to a key
+                // of its own until it comes back. Paying a loan back returns
+                // the lots it brought in without a gain; anything else paid
+                // back is disposed of at its value.
+                let repayment = delta;
+                let mut repaid = None;
+                let rest_of_repayment;
+                let (delta, total_revenue) = match loans::role(delta) {
+                    Some(loans::LoanRole::PostCollateral) => {
+                        let posted = self.take_collateral(&key, delta, fallback_method.for_transfers(), rules)
+                            .and_then(|(lots, short)| Ok((lots, short, delta.value(quote_currency, prices)?)));
+                        let (mut lots, short, value) = match posted {
+                            Ok(posted) => posted,
+                            Err(err) => {
+                                errors.push(err.at(group_index, delta));
+                                continue
+                            },
+                        };
+                        shortfalls.extend(short);
+                        ledger.0.push(loans::LoanEntry::new(loans::LoanRole::PostCollateral, delta, value, lots.iter().map(|lot| lot.cost).sum()));
+                        let held = self.0.entry(loans::collateral_key(delta)).or_default();
+                        held.append(&mut lots);
+                        held.sort_by_key(|lot| lot.timestamp);
+                        continue
                     },
-                    InventoryMethod::Yipo => {
-                        let symbol = symbols::delta_tax_ticker(&delta);
-                        while rem_qty > 0.0 {
-
-                            if self.0[&symbol].len() == 0 {
-                                println!("neg acq_vec: {} from delta: {:#?}", rem_qty, delta);
-
-                                self.0.get_mut(&symbol).unwrap().push ( Lot {
-                                    timestamp: 0,
-                                    qty: -rem_qty,
-                                    cost: 0.0,
-                                    host: None,
-                                    identifier: None,
-
-                                });
-                                rem_qty = 0.0
-                            } else if delta.timestamp - self.0[&symbol][0].timestamp >= MILLIS_YEAR {
-
-                                if rem_qty >= self.0[&symbol][0].qty {
-                                    let removed = self.0.get_mut(&symbol).unwrap().remove(0);
-                                    rem_qty -= removed.qty;
-                                    removed_lots.push(removed);
-                                } else {
-                                    let removed = self.0.get_mut(&symbol).unwrap()[0].remove_qty(rem_qty);
-                                    rem_qty -= removed.qty;
-                                    assert!(rem_qty == 0.0);
-                                    removed_lots.push(removed);
-                                }
-
-
-                            } else {
-
-                                if rem_qty >= self.0[&symbol].last().unwrap().qty {
-                                    let removed = self.0.get_mut(&symbol).unwrap().pop().unwrap();
-                                    rem_qty -= removed.qty;
-                                    removed_lots.push(removed);
-                                } else {
-                                    let last_index = self.0[&symbol].len() - 1;
-                                    let removed = self.0.get_mut(&symbol).unwrap()[ last_index ].remove_qty(rem_qty);
-                                    rem_qty -= removed.qty;
-                                    assert!(rem_qty == 0.0);
-                                    removed_lots.push(removed);
-                                }
-                            }
+                    Some(loans::LoanRole::Repay) => {
+                        let returned = self.take_borrowed(&key, delta.qty)
+                            .and_then(|lots| Ok((lots, delta.value(quote_currency, prices)?)));
+                        let (lots, value) = match returned {
+                            Ok(returned) => returned,
+                            Err(err) => {
+                                errors.push(err.at(group_index, delta));
+                                continue
+                            },
+                        };
+                        let basis: f64 = lots.iter().map(|lot| lot.cost).sum();
+                        let rest = delta.qty - lots.iter().map(|lot| lot.qty).sum::<f64>();
+                        if rest <= specific_id::QTY_TOLERANCE {
+                            ledger.0.push(loans::LoanEntry::new(loans::LoanRole::Repay, delta, value, basis));
+                            continue
                         }
+                        repaid = Some((value, basis));
+                        rest_of_repayment = deltas::Delta { qty: rest, ..delta.clone() };
+                        (&rest_of_repayment, value * rest / delta.qty)
+                    },
+                    _ => (delta, total_revenue),
+                };
+
+                // A removal that closes a position takes all of it, float
+                // dust included, so no basis is left behind.
+                let held: f64 = self.0.get(&key).map_or(0.0, |lots| lots.iter().map(|lot| lot.qty).sum());
+                let closes = position_assets::is_position(&delta.asset) && positions.remove(&key, delta, held);
+                let whole_position;
+                let delta = if closes {
+                    whole_position = deltas::Delta { qty: held.max(delta.qty), ..delta.clone() };
+                    &whole_position
+                } else {
+                    delta
+                };
+
+                let taken = self.take_lots_for(&key, delta, selections, &mut used_selections, fallback_method, total_revenue, &rules.holding_period);
+                let mut removed_lots = match taken {
+                    Ok(lots) => lots,
+                    Err(err) => {
+                        errors.push(err.at(group_index, delta));
+                        continue
+                    },
+                };
+
+                let removed_qty: f64 = removed_lots.iter().map(|lot| lot.qty).sum();
+                if (removed_qty - delta.qty).abs() <= specific_id::QTY_TOLERANCE {
+                    made_selections.selections.push(specific_id::LotSelection::from_lots(delta, &removed_lots));
+                }
+
+                // Quantity and proceeds left to share among the rows for
+                // this disposal.
+                let mut remaining_qty = delta.qty;
+                let mut remaining_revenue = total_revenue;
+                let missing = delta.qty - removed_qty;
+                if missing > specific_id::QTY_TOLERANCE {
+                    if rules.shortfall_policy == shortfall::ShortfallPolicy::Error {
+                        errors.push(shortfall_error(&symbol, missing).at(group_index, delta));
+                        continue
+                    }
+                    shortfalls.push(shortfall::Shortfall::new(&symbol, delta, missing));
+                    match rules.shortfall_policy {
+                        shortfall::ShortfallPolicy::LaterAcquisition => {
+                            pending.entry(key.clone()).or_default().push(PendingShortfall {
+                                index: shortfalls.len() - 1,
+                                symbol: symbol.clone(),
+                                delta: delta.clone(),
+                                revenue: total_revenue * missing / delta.qty,
+                                qty: missing,
+                                carried: false,
+                                to: None,
+                            });
+                            remaining_qty -= missing;
+                            remaining_revenue -= total_revenue * missing / delta.qty;
+                        },
+                        // Acquired at the disposal itself, so zero basis
+                        // and short term. (`Error` has already failed.)
+                        shortfall::ShortfallPolicy::Error | shortfall::ShortfallPolicy::ZeroBasis => {
+                            removed_lots.push(Lot {
+                                timestamp: delta.timestamp,
+                                qty: missing,
+                                cost: 0.0,
+                                host: Some(delta.host.clone()),
+                                account: Some(delta.account.clone()),
+                                identifier: None,
+                                borrowed: false,
+                            });
+                        },
                     }
                 }
 
 
-                self.remove_empty_positions(&symbols::delta_tax_ticker(&delta), &smallest_liquidity_deltas);
+                if closes {
+                    self.0.remove(&key);
+                }
 
 
-                let mut rev = 0_f64;
+                for (i, rem_acq) in removed_lots.iter().enumerate() {
 
-                for rem_acq in &removed_lots {
+                    // The last lot takes what is left, so the rows of a
+                    // disposal add up to its quantity and proceeds exactly.
+                    let (qty, revenue) = if i == removed_lots.len() - 1 {
+                        (remaining_qty, remaining_revenue)
+                    } else {
+                        (rem_acq.qty, total_revenue * (rem_acq.qty / delta.qty))
+                    };
+                    remaining_qty -= qty;
+                    remaining_revenue -= revenue;
 
-                    let revenue = total_revenue * (rem_acq.qty / delta.qty);
-                    rev += revenue;
+                    let long_term = rules.holding_period.is_long_term(rem_acq.timestamp, delta.timestamp);
 
-                    if self.0["USDC"][0].qty < 0.0 {
+                    // Theft and scam losses are their own category, not a
+                    // capital loss.
+                    if delta.ilk == deltas::Ilk::Loss {
+                        casualty_losses += rem_acq.cost - revenue;
+                        if symbol != quote_currency {
+                            events.0.push(disposition::Disposition::new(&symbol, delta, rem_acq, qty, revenue, long_term, method));
+                        }
+                        continue
                     }
 
-
                     let gain = revenue - rem_acq.cost;
 
-                    let term = if delta.timestamp - rem_acq.timestamp > MILLIS_YEAR {
+                    if long_term {
                         long_term_capital_gains += gain;
                         if delta.asset == "LINK" {
                             link_only_long_term += gain;
                         }
-                        "long".to_string()
                     } else {
                         short_term_capital_gains += gain;
                         if delta.asset == "LINK" {
                             link_only_short_term += gain;
                         }
-                        "short".to_string()
-                    };
+                    }
 
                     if gain > lowest_gain {
                         println!("");
                         println!("{}", gain);
 
                         println!("disposition worth {} on {}", revenue, Utc.timestamp_millis(delta.timestamp as i64).to_string());
-                        if !deltas::is_uni_cl_position(&delta.asset) {
-                            println!("from: {} of {}", rem_acq.qty/delta.qty, delta.value(quote_currency, &prices));
+                        if !position_assets::is_position(&delta.asset) {
+                            if let Ok(value) = delta.value(quote_currency, prices) {
+                                println!("from: {} of {}", rem_acq.qty/delta.qty, value);
+                            }
                         }
                         println!("{:#?}", delta);
 
@@ -342,68 +743,431 @@ impl Inventory {
                         lowest_gain = gain;
                     }
                     if symbol != quote_currency {
-                        events += &format!
-                            (
-                            "{},{:.8},{},{},{:.8},{:.8},{:.8},{}\n",
-                            symbol,
-                            delta.qty,
-                            Utc.timestamp_millis(delta.timestamp as i64).to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-                            Utc.timestamp_millis(rem_acq.timestamp as i64).to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-                            revenue,
-                            rem_acq.cost,
-                            revenue - rem_acq.cost,
-                            term
-                            )
+                        events.0.push(disposition::Disposition::new(&symbol, delta, rem_acq, qty, revenue, long_term, method));
                     }
 
                 }
 
+                if let Some((value, basis)) = repaid {
+                    ledger.0.push(loans::LoanEntry::new(loans::LoanRole::Repay, repayment, value, basis + removed_lots.iter().map(|lot| lot.cost).sum::<f64>()));
+                }
+                if loans::role(delta) == Some(loans::LoanRole::Interest) {
+                    let value = if delta.asset == quote_currency { delta.qty } else { total_revenue };
+                    ledger.0.push(loans::LoanEntry::new(loans::LoanRole::Interest, delta, value, removed_lots.iter().map(|lot| lot.cost).sum()));
+                }
+
             }
         }
 
+        // Short transfers no later acquisition covered deliver the rest at
+        // zero basis, which may cover the receiving wallet's shortfalls.
+        let mut undelivered = Vec::new();
+        for waiting in pending.values_mut() {
+            let (transfers, disposals): (Vec<PendingShortfall>, Vec<PendingShortfall>) = std::mem::take(waiting).into_iter().partition(|w| w.to.is_some());
+            *waiting = disposals;
+            undelivered.extend(transfers);
+        }
+        undelivered.sort_by_key(|waiting| waiting.index);
+        for waiting in undelivered.into_iter().filter(|waiting| waiting.qty > specific_id::QTY_TOLERANCE) {
+            let zero_basis = Lot {
+                timestamp: waiting.delta.timestamp,
+                qty: waiting.qty,
+                cost: 0.0,
+                host: Some(waiting.delta.host.clone()),
+                account: Some(waiting.delta.account.clone()),
+                identifier: None,
+                borrowed: false,
+            };
+            let to = waiting.to.unwrap_or_default();
+            match self.settle(&to, zero_basis, waiting.delta.timestamp, &mut pending, &mut shortfalls) {
+                Ok(settled) => for settlement in settled {
+                    settlement.record(quote_currency, method, &mut short_term_capital_gains, &mut casualty_losses, &mut events);
+                },
+                Err(err) => errors.push(err),
+            }
+        }
 
-        let inventory_method = match method {
-            InventoryMethod::Fifo => "FIFO".to_string(),
-            InventoryMethod::Lifo => "LIFO".to_string(),
-            InventoryMethod::Yipo => "Specific_Id".to_string(),
-        };
+        // Shortfalls no later acquisition covered fall back to zero basis,
+        // and are owed to next year's acquisitions.
+        let mut pending: Vec<PendingShortfall> = pending.into_values().flatten().collect();
+        pending.sort_by_key(|waiting| waiting.index);
+        let owed: Vec<shortfall::Owed> = pending.iter()
+            .filter(|waiting| waiting.qty > specific_id::QTY_TOLERANCE)
+            .map(|waiting| shortfall::Owed { asset: waiting.symbol.clone(), delta: waiting.delta.clone(), qty: waiting.qty })
+            .collect();
+        for waiting in pending.iter().filter(|waiting| !waiting.carried) {
+            let revenue = waiting.revenue;
+            short_term_capital_gains += revenue;
+            if waiting.symbol != quote_currency {
+                let zero_basis = Lot {
+                    timestamp: waiting.delta.timestamp,
+                    qty: waiting.qty,
+                    cost: 0.0,
+                    host: Some(waiting.delta.host.clone()),
+                    account: Some(waiting.delta.account.clone()),
+                    identifier: None,
+                    borrowed: false,
+                };
+                events.0.push(disposition::Disposition::new(&waiting.symbol, &waiting.delta, &zero_basis, waiting.qty, revenue, false, method));
+            }
+        }
 
         let summary = CapitalGainsSummary {
-            inventory_method: inventory_method,
+            inventory_method: method.label(),
             income: income,
+            income_by_category,
+            fees: fee_totals,
+            casualty_losses,
+            loan_interest: ledger.interest_paid(),
             long_term_capital_gains: long_term_capital_gains,
             short_term_capital_gains: short_term_capital_gains,
         };
         println!("link_only: long: {}, short: {}", link_only_long_term, link_only_short_term);
         println!("test_gain: {}", test_gain);
-        (summary, events)
+        if let Some(selections) = selections {
+            let unused = selections.unused(&used_selections);
+            if !unused.is_empty() {
+                let listed: Vec<String> = unused.iter().map(|s| format!("{} {} ({:?})", s.asset, s.identifier, s.ilk)).collect();
+                errors.push(error::CalcError::Setup { reason: format!("{} lot selection(s) were never used: {}", unused.len(), listed.join(", ")) });
+            }
+        }
+        if !errors.is_empty() {
+            return Err(error::CalcErrors(errors));
+        }
+        Ok(Calculation {
+            summary,
+            dispositions: events,
+            selections: made_selections,
+            shortfalls,
+            loans: ledger,
+            positions,
+            unmatched_migrations,
+            owed,
+        })
 
     }
 
-    fn remove_empty_positions(&mut self, asset: &str, smallet_by_pair: &HashMap<String, f64>) {
+    /// Removes the lots for `delta` from `key`: those the first unused entry
+    /// in `selections` names, if there is one, otherwise by `method`.
+    #[allow(clippy::too_many_arguments)]
+    fn take_lots_for(&mut self, key: &str, delta: &deltas::Delta, selections: Option<&specific_id::LotSelections>, used: &mut std::collections::HashSet<usize>, method: InventoryMethod, proceeds: f64, holding_period: &HoldingPeriod) -> Result<Vec<Lot>, error::CalcError> {
+        self.0.entry(key.to_string()).or_default();
+        match selections.and_then(|s| s.find_unused(delta, used)) {
+            Some((index, selection)) => {
+                used.insert(index);
+                self.take_selected_lots(key, delta, selection)
+            },
+            None => self.take_lots(key, delta, method, proceeds, holding_period),
+        }
+    }
 
-        if deltas::is_uni_cl_position(asset) {
+    /// Removes `delta.qty` from the lots under `key` following `method`,
+    /// returning the lots (or parts of lots) removed, which add up to less
+    /// than `delta.qty` when the lots run out. `proceeds` is the
+    /// disposal's total revenue, used by `MinTax` to price each lot.
+    fn take_lots(&mut self, key: &str, delta: &deltas::Delta, method: InventoryMethod, proceeds: f64, holding_period: &HoldingPeriod) -> Result<Vec<Lot>, error::CalcError> {
+        let no_selection = || error::CalcError::Lots { asset: key.to_string(), reason: "no lot selection for this disposal".to_string() };
+        if method == InventoryMethod::SpecificId {
+            return Err(no_selection());
+        }
+        let unit_proceeds = proceeds / delta.qty;
+        let lots = self.0.entry(key.to_string()).or_default();
+        let mut rem_qty = delta.qty;
+        let mut removed_lots = Vec::new();
+
+        while rem_qty > 0.0 && !lots.is_empty() {
+            let index = match method {
+                InventoryMethod::Fifo => 0,
+                InventoryMethod::Lifo => lots.len() - 1,
+                // Long-term lots oldest first, then short-term lots newest first.
+                InventoryMethod::Yipo => if holding_period.is_long_term(lots[0].timestamp, delta.timestamp) { 0 } else { lots.len() - 1 },
+                InventoryMethod::Hifo => highest_unit_cost_index(lots),
+                InventoryMethod::MinTax(rates) => greedy_lowest_tax_index(lots, delta.timestamp, unit_proceeds, &rates, holding_period),
+                InventoryMethod::SpecificId => return Err(no_selection()),
+            };
+            let removed = if rem_qty >= lots[index].qty {
+                lots.remove(index)
+            } else {
+                lots[index].remove_qty(rem_qty, key)?
+            };
+            rem_qty -= removed.qty;
+            removed_lots.push(removed);
+        }
+        Ok(removed_lots)
+    }
 
+    /// Moves lots for the group's token migrations and renames that
+    /// `rules.migrations` pairs, from the old asset to the new, keeping
+    /// acquisition dates, basis and provenance and scaling quantities by
+    /// the ratio.
+    /// Migrating more than was held is an error under
+    /// `ShortfallPolicy::Error`. Otherwise it is returned as a shortfall and
+    /// the new asset gets the missing quantity at zero basis, since the
+    /// retired asset has no later acquisitions to take basis from. Along
+    /// with the shortfalls, returns the migration deltas nothing paired.
+    fn migrate_lots<'a>(&mut self, group: &'a deltas::DeltaGroup, rules: &Rules) -> MigrationResult<'a> {
+        let (pairs, unmatched) = rules.migrations.pair(group, &rules.holding_period);
+        let mut shortfalls = Vec::new();
+        for (out, receiving, migration) in pairs {
+            let from = rules.lot_key(out);
+            self.0.entry(from.clone()).or_default();
+            let mut moved = self.take_lots(&from, out, InventoryMethod::Fifo, 0.0, &rules.holding_period).map_err(|err| (err, out))?;
+
+            let missing = out.qty - moved.iter().map(|lot| lot.qty).sum::<f64>();
+            if missing > specific_id::QTY_TOLERANCE {
+                if rules.shortfall_policy == shortfall::ShortfallPolicy::Error {
+                    return Err((error::CalcError::Lots {
+                        asset: migration.from.clone(),
+                        reason: format!("migrating {} more than was held", missing),
+                    }, out));
+                }
+                shortfalls.push(shortfall::Shortfall::new(&migration.from, out, missing));
+                moved.push(Lot {
+                    timestamp: out.timestamp,
+                    qty: missing,
+                    cost: 0.0,
+                    host: Some(receiving.host.clone()),
+                    account: Some(receiving.account.clone()),
+                    identifier: None,
+                    borrowed: false,
+                });
+            }
 
-            let remove = if self.0[asset].len() == 0 {
-                true
-            } else if self.0[asset].len() == 1 {
+            for lot in &mut moved {
+                lot.qty *= migration.ratio;
+            }
+            let lots = self.0.entry(rules.lot_key(receiving)).or_default();
+            lots.append(&mut moved);
+            lots.sort_by_key(|lot| lot.timestamp);
+        }
+        Ok((shortfalls, unmatched))
+    }
 
-                let sym = uni_cl_pair_name(asset);
-                if self.0[asset][0].qty < smallet_by_pair[&sym] {
-                    true
-                } else {
-                    false
+    /// Moves lots between our own wallets for the group's `WalletTransfer`
+    /// deltas, keeping their acquisition dates and basis. Which lots move is
+    /// decided by `method`, as for a disposal. A transfer of more than the
+    /// sending wallet holds follows `rules.shortfall_policy`, with the
+    /// receiving wallet getting the missing quantity at zero basis or, under
+    /// `LaterAcquisition`, from the sending wallet's next acquisitions. Lots
+    /// that arrive cover the receiving wallet's waiting shortfalls first;
+    /// those that cover a disposal are returned to be reported. Errors come
+    /// with the sending delta they concern.
+    fn transfer_lots<'a>(&mut self, group: &'a deltas::DeltaGroup, method: InventoryMethod, rules: &Rules, pending: &mut HashMap<String, Vec<PendingShortfall>>, shortfalls: &mut Vec<shortfall::Shortfall>) -> Result<Vec<Settlement>, (error::CalcError, &'a deltas::Delta)> {
+        let mut settled = Vec::new();
+        for out in group.outs.iter().filter(|d| d.ilk == deltas::Ilk::WalletTransfer) {
+            // Spam lots aren't tracked per wallet.
+            if self.0.contains_key(&out.spam_id()) {
+                continue
+            }
+            let ticker = symbols::delta_tax_ticker(out);
+            let destination = group.ins.iter()
+                .find(|d| d.ilk == deltas::Ilk::WalletTransfer && symbols::delta_tax_ticker(d) == ticker)
+                .ok_or_else(|| (error::CalcError::Lots { asset: ticker.clone(), reason: "wallet transfer has no receiving side".to_string() }, out))?;
+            if (destination.qty - out.qty).abs() > specific_id::QTY_TOLERANCE {
+                return Err((error::CalcError::Lots {
+                    asset: ticker.clone(),
+                    reason: format!("wallet transfer receives {}; fees need their own deltas", destination.qty),
+                }, out));
+            }
+
+            let from = wallet_key(&ticker, &out.host, &out.account);
+            let to = wallet_key(&ticker, &destination.host, &destination.account);
+            if !self.0.contains_key(&from) {
+                self.0.insert(from.clone(), vec![]);
+            }
+            let mut moved = self.take_lots(&from, out, method.for_transfers(), 0.0, &rules.holding_period).map_err(|err| (err, out))?;
+
+            let missing = out.qty - moved.iter().map(|lot| lot.qty).sum::<f64>();
+            if missing > specific_id::QTY_TOLERANCE {
+                match rules.shortfall_policy {
+                    shortfall::ShortfallPolicy::Error => return Err((shortfall_error(&ticker, missing), out)),
+                    shortfall::ShortfallPolicy::ZeroBasis => {
+                        shortfalls.push(shortfall::Shortfall::new(&ticker, out, missing));
+                        moved.push(Lot {
+                            timestamp: out.timestamp,
+                            qty: missing,
+                            cost: 0.0,
+                            host: Some(destination.host.clone()),
+                            account: Some(destination.account.clone()),
+                            identifier: None,
+                            borrowed: false,
+                        });
+                    },
+                    shortfall::ShortfallPolicy::LaterAcquisition => {
+                        shortfalls.push(shortfall::Shortfall::new(&ticker, out, missing));
+                        pending.entry(from.clone()).or_default().push(PendingShortfall {
+                            index: shortfalls.len() - 1,
+                            symbol: ticker.clone(),
+                            delta: out.clone(),
+                            revenue: 0.0,
+                            qty: missing,
+                            carried: false,
+                            to: Some(to.clone()),
+                        });
+                    },
                 }
+            }
+
+            for lot in moved {
+                settled.extend(self.settle(&to, lot, out.timestamp, pending, shortfalls).map_err(|err| (err, out))?);
+            }
+        }
+        Ok(settled)
+    }
+
+    /// Gives `acq`, arriving under `key` at `at`, to the shortfalls waiting
+    /// there, oldest first, and holds whatever is left of it. A piece that
+    /// covers a disposal is returned to be reported; one that covers a short
+    /// wallet transfer moves on to the receiving wallet, where it may cover
+    /// that wallet's shortfalls in turn.
+    fn settle(&mut self, key: &str, mut acq: Lot, at: u64, pending: &mut HashMap<String, Vec<PendingShortfall>>, shortfalls: &mut [shortfall::Shortfall]) -> Result<Vec<Settlement>, error::CalcError> {
+        let mut settled = Vec::new();
+        while acq.qty > 0.0 {
+            let Some(waiting) = pending.get_mut(key).and_then(|waiting| waiting.first_mut()) else {
+                break
+            };
+            let covered = if waiting.qty >= acq.qty {
+                let whole = acq.clone();
+                acq.qty = 0.0;
+                acq.cost = 0.0;
+                whole
             } else {
-                dbg!(&self.0[asset]);
-                panic!("");
+                acq.remove_qty(waiting.qty, key)?
             };
+            // The piece that finishes a shortfall takes whatever is left of
+            // its proceeds, so the pieces add up exactly.
+            let finished = waiting.qty - covered.qty <= specific_id::QTY_TOLERANCE;
+            let (qty, revenue) = if finished {
+                (waiting.qty, waiting.revenue)
+            } else {
+                (covered.qty, waiting.revenue * covered.qty / waiting.qty)
+            };
+            waiting.qty -= qty;
+            waiting.revenue -= revenue;
+            shortfalls[waiting.index].resolved_by.push(covered.clone());
+
+            let to = waiting.to.clone();
+            // Last year reported a carried shortfall with all its proceeds
+            // and no basis, so the basis found now gets a row of its own,
+            // with no proceeds, dated this year.
+            let mut disposal = waiting.delta.clone();
+            if waiting.carried {
+                disposal.timestamp = at;
+            }
+            let symbol = waiting.symbol.clone();
+            if finished {
+                pending.get_mut(key).unwrap().remove(0);
+            }
+            match to {
+                Some(to) => settled.extend(self.settle(&to, covered, at, pending, shortfalls)?),
+                None => settled.push(Settlement { symbol, disposal, covered, qty, revenue }),
+            }
+        }
 
-            if remove {
-                self.0.remove(asset);
+        if acq.qty > 0.0 {
+            let lots = self.0.entry(key.to_string()).or_default();
+            lots.push(acq);
+            lots.sort_by_key(|lot| lot.timestamp);
+        }
+        Ok(settled)
+    }
+
+    /// The same lots with every wallet's holdings of a ticker merged under
+    /// the ticker, as a pooled inventory would hold them.
+    pub fn pooled(&self) -> Self {
+        let mut pooled: HashMap<String, Vec<Lot>> = HashMap::new();
+        for (key, lots) in &self.0 {
+            pooled.entry(key_ticker(key).to_string()).or_default().extend(lots.iter().cloned());
+        }
+        for lots in pooled.values_mut() {
+            lots.sort_by_key(|lot| lot.timestamp);
+        }
+        Self ( pooled )
+    }
+
+    /// Removes `delta.qty` from the lots under `key` for collateral moving
+    /// to or from a loan, along with the shortfall if they run out. The
+    /// missing quantity comes at zero basis, or is an error under
+    /// `ShortfallPolicy::Error`.
+    fn take_collateral(&mut self, key: &str, delta: &deltas::Delta, method: InventoryMethod, rules: &Rules) -> Result<(Vec<Lot>, Option<shortfall::Shortfall>), error::CalcError> {
+        let ticker = symbols::delta_tax_ticker(delta);
+        let mut lots = self.take_lots(key, delta, method, 0.0, &rules.holding_period)?;
+        let missing = delta.qty - lots.iter().map(|lot| lot.qty).sum::<f64>();
+        if missing <= specific_id::QTY_TOLERANCE {
+            return Ok((lots, None))
+        }
+        if rules.shortfall_policy == shortfall::ShortfallPolicy::Error {
+            return Err(shortfall_error(&ticker, missing));
+        }
+        lots.push(Lot {
+            timestamp: delta.timestamp,
+            qty: missing,
+            cost: 0.0,
+            host: Some(delta.host.clone()),
+            account: Some(delta.account.clone()),
+            identifier: None,
+            borrowed: false,
+        });
+        Ok((lots, Some(shortfall::Shortfall::new(&ticker, delta, missing))))
+    }
+
+    /// Removes up to `qty` from the borrowed lots under `key`, oldest first.
+    fn take_borrowed(&mut self, key: &str, qty: f64) -> Result<Vec<Lot>, error::CalcError> {
+        let mut taken = Vec::new();
+        let Some(lots) = self.0.get_mut(key) else {
+            return Ok(taken)
+        };
+        let mut rem_qty = qty;
+        while rem_qty > specific_id::QTY_TOLERANCE {
+            let Some(index) = lots.iter().position(|lot| lot.borrowed) else {
+                break
+            };
+            let lot = if rem_qty >= lots[index].qty {
+                lots.remove(index)
+            } else {
+                lots[index].remove_qty(rem_qty, key)?
+            };
+            rem_qty -= lot.qty;
+            taken.push(lot);
+        }
+        Ok(taken)
+    }
+
+    /// Removes exactly the lots a specific identification selection names.
+    /// A selection that doesn't match what is held, or that doesn't add up
+    /// to the disposal, is a hard error.
+    fn take_selected_lots(&mut self, key: &str, delta: &deltas::Delta, selection: &specific_id::LotSelection) -> Result<Vec<Lot>, error::CalcError> {
+        let symbol = key.to_string();
+        let lots_error = |reason: String| error::CalcError::Lots { asset: symbol.clone(), reason };
+
+        let selected_qty: f64 = selection.lots.iter().map(|l| l.qty).sum();
+        if (selected_qty - delta.qty).abs() > specific_id::QTY_TOLERANCE {
+            return Err(lots_error(format!("lot selection covers {} but the disposal is {}", selected_qty, delta.qty)));
+        }
+
+        let mut removed_lots = Vec::new();
+        for selected in &selection.lots {
+            if selected.qty <= 0.0 {
+                return Err(lots_error(format!("lot selection wants {} from lot {:?} at {}; quantities must be positive", selected.qty, selected.identifier, selected.timestamp)));
+            }
+            let lots = self.0.get_mut(&symbol)
+                .ok_or_else(|| lots_error("lot selection for an asset not held".to_string()))?;
+            let index = lots.iter()
+                .position(|lot| lot.identifier == selected.identifier && lot.timestamp == selected.timestamp)
+                .ok_or_else(|| lots_error(format!("lot selection names lot {:?} at {} which isn't held", selected.identifier, selected.timestamp)))?;
+
+            if selected.qty > lots[index].qty + specific_id::QTY_TOLERANCE {
+                return Err(lots_error(format!("lot selection wants {} from lot {:?} at {} which only has {}",
+                    selected.qty, selected.identifier, selected.timestamp, lots[index].qty)));
+            }
+
+            if selected.qty >= lots[index].qty {
+                removed_lots.push(lots.remove(index));
+            } else {
+                removed_lots.push(lots[index].remove_qty(selected.qty, &symbol)?);
             }
         }
+        Ok(removed_lots)
     }
 }
 
@@ -415,7 +1179,7 @@ pub struct TaxableTotalsCanada {
     pub capital_gains: f64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Holding {
     pub qty: f64,
     pub cost: f64,
@@ -446,7 +1210,6 @@ impl ConsolidatedInventory {
                 cost: 0_f64,
 
             };
-            assert!(!holdings_inner.contains_key(asset));
             holdings_inner.insert(asset.clone(), h);
         }
         Self ( holdings_inner )
@@ -466,60 +1229,72 @@ impl ConsolidatedInventory {
     }
 
 
+    /// Adds the holding under `alias` to `name`. Nothing held under
+    /// `alias` leaves the inventory as it was.
     pub fn consolidate_alias(&mut self, name: &str, alias: &str) {
 
-        self.0.get_mut(name).unwrap().qty += self.0[alias].qty;
-        self.0.get_mut(name).unwrap().cost += self.0[alias].cost;
-        self.0.remove(alias);
+        if let Some(aliased) = self.0.remove(alias) {
+            let holding = self.0.entry(name.to_string()).or_default();
+            holding.qty += aliased.qty;
+            holding.cost += aliased.cost;
+        }
     }
 
-    pub fn apply_deltas(&mut self, linked_deltas: &deltas::LinkedDeltas, quote_currency: &str, prices: &prices::Prices) -> (TaxableTotalsCanada, String) {
+    pub fn apply_deltas(&mut self, linked_deltas: &deltas::LinkedDeltas, quote_currency: &str, prices: &prices::Prices) -> Result<(TaxableTotalsCanada, String), error::CalcError> {
         let mut events = "asset,quantity,disposition_date,proceeds_CAD,cost_basis_CAD,capital_gain_CAD\n".to_string();
 
         let mut capital_gains = 0_f64;
+        let rules = Rules::default();
         let mut income = 0_f64;
 
-        for group in &linked_deltas.0 {
+        for (group_index, group) in linked_deltas.0.iter().enumerate() {
             // Process Ins
             for delta in &group.ins {
-                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration {
+                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration || delta.ilk == deltas::Ilk::WalletTransfer {
                     continue
                 }
 
                 let symbol = symbols::delta_tax_ticker(&delta);
 
-                income += group.income_for(delta, quote_currency, &prices);
+                if let Some((_, amount)) = group.income_for(delta, quote_currency, prices, &rules).map_err(|err| err.at(group_index, delta))? {
+                    income += amount;
+                }
 
-                let cost = group.cost_for(delta, quote_currency, &prices);
+                let cost = group.cost_for(delta, quote_currency, prices, &rules).map_err(|err| err.at(group_index, delta))?;
 
                 if cost < 0.0 {
-                    panic!("");
+                    return Err(error::CalcError::UnexpectedDelta { asset: delta.asset.clone(), reason: format!("negative cost {}", cost) }.at(group_index, delta));
                 }
 
-                if self.0[&symbol].qty < 0.0 {
-                    println!("neg acq_vec: {} from delta: {:#?}", self.0[&symbol].qty, delta);
-
-                    assert!(delta.identifier == "0x32eeca6efe92db4119b412a172a909582d7c47a6830ee7c6f1cc334b0e70b0c4" || self.0[&symbol].qty.abs()  < 0.0000001);
-                    assert!(self.0[&symbol].qty.abs() < delta.qty);
+                let holding = self.0.entry(symbol.clone()).or_default();
+                // An acquisition may make good an earlier overdraw, but only
+                // if it covers all of it.
+                if holding.qty < 0.0 && holding.qty.abs() >= delta.qty {
+                    return Err(error::CalcError::Lots {
+                        asset: symbol,
+                        reason: format!("acquiring {} leaves the holding of {} negative", delta.qty, holding.qty),
+                    }.at(group_index, delta));
                 }
 
-                self.0.get_mut(&symbol).unwrap().qty += delta.qty;
-                self.0.get_mut(&symbol).unwrap().cost += group.cost_for(delta, quote_currency, prices);
+                holding.qty += delta.qty;
+                holding.cost += cost;
             }
 
             // Process Outs
             for delta in &group.outs {
-                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration {
+                if delta.ilk == deltas::Ilk::WrapEth || delta.ilk == deltas::Ilk::UnwrapEth || delta.ilk == deltas::Ilk::TokenMigration || delta.ilk == deltas::Ilk::WalletTransfer {
                     continue
                 }
 
                 let symbol = symbols::delta_tax_ticker(&delta);
-                let total_revenue = group.revenue_for(delta, quote_currency, prices);
+                let total_revenue = group.revenue_for(delta, quote_currency, prices, &rules).map_err(|err| err.at(group_index, delta))?;
 
-                let cost_basis = self.0[&symbol].cost_basis(delta.qty);
+                let holding = self.0.get_mut(&symbol)
+                    .ok_or_else(|| error::CalcError::Lots { asset: symbol.clone(), reason: "disposal of an asset not held".to_string() }.at(group_index, delta))?;
+                let cost_basis = holding.cost_basis(delta.qty);
 
-                self.0.get_mut(&symbol).unwrap().qty -= delta.qty;
-                self.0.get_mut(&symbol).unwrap().cost -= cost_basis;
+                holding.qty -= delta.qty;
+                holding.cost -= cost_basis;
 
                 capital_gains += (total_revenue - cost_basis);
 
@@ -542,49 +1317,511 @@ impl ConsolidatedInventory {
             income: income,
             capital_gains: capital_gains,
         };
-        (summary, events)
+        Ok((summary, events))
     }
 
 }
 
-/// Finds the smallest position quantity per token pair across all Uniswap
-/// concentrated-liquidity positions (V3 and V4). Used for dust cleanup
-/// — positions smaller than this threshold can be discarded.
-fn smallet_by_pair(linked_deltas: &deltas::LinkedDeltas) -> HashMap<String, f64> {
+/// What `Inventory::migrate_lots` returns: the shortfalls and unpaired
+/// migration deltas, or an error with the delta it concerns.
+type MigrationResult<'a> = Result<(Vec<shortfall::Shortfall>, Vec<migrations::Unmatched>), (error::CalcError, &'a deltas::Delta)>;
+
+/// A shortfall under `ShortfallPolicy::LaterAcquisition` still waiting
+/// for acquisitions to take its basis from.
+struct PendingShortfall {
+    /// Index into the shortfalls `apply_deltas` returns.
+    index: usize,
+    symbol: String,
+    /// The disposal that came up short.
+    delta: deltas::Delta,
+    /// Proceeds of the quantity still uncovered.
+    revenue: f64,
+    /// Quantity still uncovered.
+    qty: f64,
+    /// Owed from last year, which already reported its proceeds.
+    carried: bool,
+    /// For a wallet transfer that came up short, the receiving wallet's
+    /// key: the pieces that cover it move on there rather than being sold.
+    to: Option<String>,
+}
 
-    let mut smallet_by_pair: HashMap<String, f64> = HashMap::new();
-    for group in &linked_deltas.0 {
-        for d in group.all_deltas() {
+/// A piece of a later acquisition that covered part of a disposal's
+/// shortfall.
+struct Settlement {
+    symbol: String,
+    /// The disposal, dated when it was covered if last year reported it.
+    disposal: deltas::Delta,
+    covered: Lot,
+    qty: f64,
+    revenue: f64,
+}
 
-            if deltas::is_uni_cl_position(&d.asset) {
+impl Settlement {
+    /// Adds the gain, or casualty loss, to the totals and the row to
+    /// `events`.
+    fn record(self, quote_currency: &str, method: InventoryMethod, short_term_capital_gains: &mut f64, casualty_losses: &mut f64, events: &mut disposition::Dispositions) {
+        if self.disposal.ilk == deltas::Ilk::Loss {
+            *casualty_losses += self.covered.cost - self.revenue;
+        } else {
+            *short_term_capital_gains += self.revenue - self.covered.cost;
+        }
+        if self.symbol != quote_currency {
+            events.0.push(disposition::Disposition::new(&self.symbol, &self.disposal, &self.covered, self.qty, self.revenue, false, method));
+        }
+    }
+}
 
-                let sym = uni_cl_pair_name(&d.asset);
+/// The error for taking `missing` more than was held under
+/// `ShortfallPolicy::Error`.
+fn shortfall_error(asset: &str, missing: f64) -> error::CalcError {
+    error::CalcError::Lots { asset: asset.to_string(), reason: format!("{} more than was held", missing) }
+}
 
-                if smallet_by_pair.contains_key(&sym) {
-                    if d.qty < smallet_by_pair[&sym] {
-                        *smallet_by_pair.get_mut(&sym).unwrap() = d.qty;
-                    }
-                } else {
-                    smallet_by_pair.insert(sym, d.qty);
-                };
+/// Index of the lot with the highest cost per unit, for HIFO. Ties go to
+/// the earliest lot so that, all else equal, the disposal is more likely
+/// to be long term.
+fn highest_unit_cost_index(lots: &[Lot]) -> usize {
+    let mut best = 0;
+    let mut best_price = f64::NEG_INFINITY;
+    for (i, lot) in lots.iter().enumerate() {
+        if lot.qty <= 0.0 {
+            continue
+        }
+        let price = lot.cost / lot.qty;
+        if price > best_price {
+            best = i;
+            best_price = price;
+        }
+    }
+    best
+}
 
-            }
+/// Index of the lot whose disposal at `unit_proceeds` adds the least tax
+/// right now, for `MinTax`. Greedy: only this disposal's tax is weighed,
+/// not what the choice leaves for later ones. Ties go to the earliest lot.
+fn greedy_lowest_tax_index(lots: &[Lot], disposed: u64, unit_proceeds: f64, rates: &TaxRates, holding_period: &HoldingPeriod) -> usize {
+    let mut best = 0;
+    let mut best_tax = f64::INFINITY;
+    for (i, lot) in lots.iter().enumerate() {
+        if lot.qty <= 0.0 {
+            continue
+        }
+        let unit_gain = unit_proceeds - lot.cost / lot.qty;
+        let tax = rates.tax(unit_gain, holding_period.is_long_term(lot.timestamp, disposed));
+        if tax < best_tax {
+            best = i;
+            best_tax = tax;
         }
     }
-    smallet_by_pair
+    best
 }
 
-/// Extracts the token pair name (e.g. "WETH-USDC") from a Uniswap
-/// concentrated-liquidity position asset identifier. Works for both V3 and V4
-/// since both formats have the two token names at underscore-delimited
-/// positions 1 and 2:
-///   V3: `UNI-V3-LIQUIDITY:{tokenId}_{token0}_{token1}_{fee}_{tickLo}_{tickHi}`
-///   V4: `UNI-V4-LIQUIDITY:{tokenId}_{token0}_{token1}_{poolId}_{tickLo}_{tickHi}`
-fn uni_cl_pair_name(full_name: &str) -> String {
-    assert!(deltas::is_uni_cl_position(full_name));
 
-    let split: Vec<&str> = full_name.split("_").collect();
-    let sym = format!("{}-{}", split[1], split[2]);
-    sym
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn long_term_starts_the_day_after_the_anniversary() {
+        let utc = HoldingPeriod::default();
+        let acquired = fixtures::noon("2022-03-01");
+        assert!(!utc.is_long_term(acquired, fixtures::millis_in(chrono_tz::UTC, "2023-03-01 23:59")));
+        assert!(utc.is_long_term(acquired, fixtures::millis_in(chrono_tz::UTC, "2023-03-02 00:00")));
+    }
+
+    #[test]
+    fn long_term_counts_dates_in_the_time_zone() {
+        let new_york = HoldingPeriod { time_zone: chrono_tz::America::New_York };
+        // 2022-03-01 in New York, already 2022-03-02 in UTC.
+        let acquired = fixtures::millis_in(chrono_tz::America::New_York, "2022-03-01 22:00");
+        let disposed = fixtures::millis_in(chrono_tz::America::New_York, "2023-03-02 09:00");
+        assert!(new_york.is_long_term(acquired, disposed));
+        assert!(!HoldingPeriod::default().is_long_term(acquired, disposed));
+    }
+
+    #[test]
+    fn leap_day_anniversary_is_february_28() {
+        let utc = HoldingPeriod::default();
+        let acquired = fixtures::noon("2024-02-29");
+        assert!(!utc.is_long_term(acquired, fixtures::noon("2025-02-28")));
+        assert!(utc.is_long_term(acquired, fixtures::noon("2025-03-01")));
+    }
+
+    #[test]
+    fn hifo_picks_the_highest_unit_cost() {
+        let lots = vec![fixtures::lot(1, 1.0, 1000.0), fixtures::lot(2, 2.0, 5000.0), fixtures::lot(3, 1.0, 2500.0)];
+        assert_eq!(highest_unit_cost_index(&lots), 1);
+    }
+
+    #[test]
+    fn hifo_ties_go_to_the_earliest_lot() {
+        let lots = vec![fixtures::lot(1, 1.0, 2000.0), fixtures::lot(2, 2.0, 4000.0)];
+        assert_eq!(highest_unit_cost_index(&lots), 0);
+    }
+
+    #[test]
+    fn hifo_skips_empty_lots() {
+        let lots = vec![fixtures::lot(1, 0.0, 9000.0), fixtures::lot(2, 1.0, 100.0)];
+        assert_eq!(highest_unit_cost_index(&lots), 1);
+    }
+
+    fn selected(timestamp: u64, qty: f64) -> specific_id::SelectedLot {
+        specific_id::SelectedLot { identifier: Some(format!("0x{}", timestamp)), timestamp, qty }
+    }
+
+    fn selections(default_method: InventoryMethod, identifier: &str, lots: Vec<specific_id::SelectedLot>) -> specific_id::LotSelections {
+        specific_id::LotSelections {
+            default_method,
+            selections: vec![specific_id::LotSelection { identifier: identifier.to_string(), asset: "ETH".to_string(), ilk: None, lots }],
+        }
+    }
+
+    fn three_lots() -> Inventory {
+        Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 1.0, 1000.0), fixtures::lot(2, 1.0, 2000.0), fixtures::lot(3, 1.0, 3000.0)])]) )
+    }
+
+    #[test]
+    fn selection_takes_the_named_lots() {
+        let sale = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Swap, "ETH", 1.5);
+        let selections = selections(InventoryMethod::Fifo, &sale.identifier, vec![selected(3, 1.0), selected(1, 0.5)]);
+        let mut inventory = three_lots();
+        let mut used = std::collections::HashSet::new();
+
+        let taken = inventory.take_lots_for("ETH", &sale, Some(&selections), &mut used, InventoryMethod::Fifo, 0.0, &HoldingPeriod::default()).unwrap();
+        let taken: Vec<(u64, f64, f64)> = taken.iter().map(|lot| (lot.timestamp, lot.qty, lot.cost)).collect();
+        assert_eq!(taken, vec![(3, 1.0, 3000.0), (1, 0.5, 500.0)]);
+        let left: Vec<(u64, f64)> = inventory.0["ETH"].iter().map(|lot| (lot.timestamp, lot.qty)).collect();
+        assert_eq!(left, vec![(1, 0.5), (2, 1.0)]);
+        assert!(used.contains(&0));
+    }
+
+    #[test]
+    fn disposal_without_a_selection_uses_the_default_method() {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 2500.0)]);
+        let selected_sale = fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 1.0);
+        let other_sale = fixtures::delta(t + 1, deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 1.0);
+        let selections = selections(InventoryMethod::Hifo, &selected_sale.identifier, vec![selected(1, 1.0)]);
+        let linked = deltas::LinkedDeltas(vec![
+            deltas::DeltaGroup { ins: Vec::new(), outs: vec![selected_sale] },
+            deltas::DeltaGroup { ins: Vec::new(), outs: vec![other_sale] },
+        ]);
+        let mut inventory = three_lots();
+
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::SpecificId, Some(&selections), &[], &Rules::default()).unwrap();
+        let acquired: Vec<u64> = calculation.dispositions.0.iter().map(|d| d.acquired).collect();
+        assert_eq!(acquired, vec![1, 3]);
+        let left: Vec<u64> = inventory.0["ETH"].iter().map(|lot| lot.timestamp).collect();
+        assert_eq!(left, vec![2]);
+    }
 
+    #[test]
+    fn selection_that_does_not_fit_is_an_error() {
+        let sale = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Swap, "ETH", 1.0);
+        let holding_period = HoldingPeriod::default();
+        let wrong_total = selections(InventoryMethod::Fifo, &sale.identifier, vec![selected(1, 0.5)]);
+        let too_much = selections(InventoryMethod::Fifo, &sale.identifier, vec![selected(1, 2.0)]);
+        let not_held = selections(InventoryMethod::Fifo, &sale.identifier, vec![selected(4, 1.0)]);
+
+        for selections in [wrong_total, too_much, not_held] {
+            let mut inventory = three_lots();
+            let mut used = std::collections::HashSet::new();
+            let taken = inventory.take_lots_for("ETH", &sale, Some(&selections), &mut used, InventoryMethod::Fifo, 0.0, &holding_period);
+            assert!(matches!(taken, Err(error::CalcError::Lots { .. })));
+        }
+    }
+
+    /// A long-term lot with a big gain, a short-term lot at a loss and a
+    /// short-term lot with a smaller gain, sold at 2500 on `fixtures::DAY`.
+    fn mintax_lots() -> Inventory {
+        Inventory ( HashMap::from([("ETH".to_string(), vec![
+            fixtures::lot(fixtures::noon("2021-01-04"), 1.0, 1000.0),
+            fixtures::lot(fixtures::noon("2022-02-01"), 1.0, 2600.0),
+            fixtures::lot(fixtures::noon("2022-02-15"), 1.0, 2000.0),
+        ])]) )
+    }
+
+    #[test]
+    fn mintax_takes_the_lots_that_add_the_least_tax() {
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 2500.0)]);
+        let sale = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 2.0);
+        let linked = deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![sale] }]);
+        let mut inventory = mintax_lots();
+
+        // Tax per lot at the default rates: 300, -37 and 185.
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::MinTax(TaxRates::default()), None, &[], &Rules::default()).unwrap();
+        let acquired: Vec<u64> = calculation.dispositions.0.iter().map(|d| d.acquired).collect();
+        assert_eq!(acquired, vec![fixtures::noon("2022-02-01"), fixtures::noon("2022-02-15")]);
+        assert_eq!(inventory.0["ETH"][0].timestamp, fixtures::noon("2021-01-04"));
+    }
+
+    #[test]
+    fn mintax_choices_replay_as_specific_identification() {
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 2500.0)]);
+        let sale = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 2.0);
+        let linked = deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![sale] }]);
+
+        let mut optimized = mintax_lots();
+        let first = optimized.apply_deltas(&linked, "USD", &prices, InventoryMethod::MinTax(TaxRates::default()), None, &[], &Rules::default()).unwrap();
+        let mut replayed = mintax_lots();
+        let second = replayed.apply_deltas(&linked, "USD", &prices, InventoryMethod::SpecificId, Some(&first.selections), &[], &Rules::default()).unwrap();
+
+        let rows = |c: &Calculation| -> Vec<(u64, f64, f64)> { c.dispositions.0.iter().map(|d| (d.acquired, d.qty, d.cost_basis)).collect() };
+        assert_eq!(rows(&first), rows(&second));
+        assert_eq!(first.summary.short_term_capital_gains, second.summary.short_term_capital_gains);
+        assert_eq!(optimized.0["ETH"].len(), replayed.0["ETH"].len());
+    }
+
+    #[test]
+    fn uncovered_shortfall_is_owed_to_next_year() {
+        let rules = Rules { shortfall_policy: shortfall::ShortfallPolicy::LaterAcquisition, ..Default::default() };
+        let prices = fixtures::prices(&[("ETH", "2022-03-01", 3000.0), ("ETH", "2022-04-01", 2000.0)]);
+        let payment = deltas::DeltaGroup {
+            ins: Vec::new(),
+            outs: vec![fixtures::delta(fixtures::noon("2022-03-01"), deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 1.0)],
+        };
+        let airdrop = deltas::DeltaGroup {
+            ins: vec![fixtures::delta(fixtures::noon("2022-04-01"), deltas::Direction::In, deltas::Ilk::Airdrop, "ETH", 2.0)],
+            outs: Vec::new(),
+        };
+
+        let mut first = Inventory ( HashMap::new() );
+        let year = first.apply_deltas(&deltas::LinkedDeltas(vec![payment]), "USD", &prices, InventoryMethod::Fifo, None, &[], &rules).unwrap();
+        assert_eq!(year.summary.short_term_capital_gains, 3000.0);
+        assert_eq!(year.owed.len(), 1);
+        assert_eq!(year.owed[0].qty, 1.0);
+
+        let mut second = first.clone();
+        let next = second.apply_deltas(&deltas::LinkedDeltas(vec![airdrop]), "USD", &prices, InventoryMethod::Fifo, None, &year.owed, &rules).unwrap();
+        // The basis that covers last year's sale is this year's loss.
+        assert_eq!(next.dispositions.0.len(), 1);
+        let row = &next.dispositions.0[0];
+        assert_eq!((row.qty, row.proceeds, row.cost_basis), (1.0, 0.0, 2000.0));
+        assert_eq!(row.disposed, fixtures::noon("2022-04-01"));
+        assert_eq!(row.disposition_identifier, format!("0x{}", fixtures::noon("2022-03-01")));
+        assert_eq!(next.summary.short_term_capital_gains, -2000.0);
+        assert!(next.owed.is_empty());
+        assert_eq!(next.shortfalls[0].resolved_by.len(), 1);
+        let lots = &second.0["ETH"];
+        assert_eq!(lots.len(), 1);
+        assert_eq!((lots[0].qty, lots[0].cost), (1.0, 2000.0));
+    }
+
+    /// A transfer of 2 ETH from `0xabc`, which holds 1 bought for 1000,
+    /// to `0xdef`, tracked per wallet under `policy`.
+    fn short_transfer(policy: shortfall::ShortfallPolicy) -> (Inventory, deltas::DeltaGroup, Rules) {
+        let rules = Rules { lot_scope: LotScope::PerWallet, shortfall_policy: policy, ..Default::default() };
+        let t = fixtures::noon(fixtures::DAY);
+        let out = fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::WalletTransfer, "ETH", 2.0);
+        let mut arrival = fixtures::delta(t, deltas::Direction::In, deltas::Ilk::WalletTransfer, "ETH", 2.0);
+        arrival.account = "0xdef".to_string();
+        let inventory = Inventory ( HashMap::from([(wallet_key("ETH", &deltas::Host::Mainnet, "0xabc"), vec![fixtures::lot(1, 1.0, 1000.0)])]) );
+        (inventory, deltas::DeltaGroup { ins: vec![arrival], outs: vec![out] }, rules)
+    }
+
+    #[test]
+    fn short_transfer_fails_at_the_transfer_under_error() {
+        let (mut inventory, transfer, rules) = short_transfer(shortfall::ShortfallPolicy::Error);
+        let prices = fixtures::prices(&[]);
+        let Err(errors) = inventory.apply_deltas(&deltas::LinkedDeltas(vec![transfer]), "USD", &prices, InventoryMethod::Fifo, None, &[], &rules) else {
+            panic!("short transfer went through");
+        };
+        assert_eq!(errors.0.len(), 1);
+        assert!(matches!(&errors.0[0], error::CalcError::Delta { group: 0, source, .. } if matches!(**source, error::CalcError::Lots { .. })));
+    }
+
+    #[test]
+    fn short_transfer_takes_basis_from_the_sending_wallets_next_acquisition() {
+        let (mut inventory, transfer, rules) = short_transfer(shortfall::ShortfallPolicy::LaterAcquisition);
+        let prices = fixtures::prices(&[("ETH", "2022-04-01", 2000.0)]);
+        let airdrop = deltas::DeltaGroup {
+            ins: vec![fixtures::delta(fixtures::noon("2022-04-01"), deltas::Direction::In, deltas::Ilk::Airdrop, "ETH", 1.5)],
+            outs: Vec::new(),
+        };
+
+        let calculation = inventory.apply_deltas(&deltas::LinkedDeltas(vec![transfer, airdrop]), "USD", &prices, InventoryMethod::Fifo, None, &[], &rules).unwrap();
+        assert!(calculation.dispositions.0.is_empty());
+        assert_eq!(calculation.shortfalls[0].resolved_by.len(), 1);
+        let received: Vec<(f64, f64)> = inventory.0[&wallet_key("ETH", &deltas::Host::Mainnet, "0xdef")].iter().map(|lot| (lot.qty, lot.cost)).collect();
+        assert_eq!(received, vec![(1.0, 1000.0), (1.0, 2000.0)]);
+        let kept: Vec<(f64, f64)> = inventory.0[&wallet_key("ETH", &deltas::Host::Mainnet, "0xabc")].iter().map(|lot| (lot.qty, lot.cost)).collect();
+        assert_eq!(kept, vec![(0.5, 1000.0)]);
+    }
+
+    #[test]
+    fn transfer_in_covers_the_receiving_wallets_shortfall() {
+        let rules = Rules { lot_scope: LotScope::PerWallet, shortfall_policy: shortfall::ShortfallPolicy::LaterAcquisition, ..Default::default() };
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0)]);
+        let t = fixtures::noon(fixtures::DAY);
+        let mut sale = fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::Payment, "ETH", 1.0);
+        sale.account = "0xdef".to_string();
+        let out = fixtures::delta(t + 1, deltas::Direction::Out, deltas::Ilk::WalletTransfer, "ETH", 1.0);
+        let mut arrival = fixtures::delta(t + 1, deltas::Direction::In, deltas::Ilk::WalletTransfer, "ETH", 1.0);
+        arrival.account = "0xdef".to_string();
+        let linked = deltas::LinkedDeltas(vec![
+            deltas::DeltaGroup { ins: Vec::new(), outs: vec![sale] },
+            deltas::DeltaGroup { ins: vec![arrival], outs: vec![out] },
+        ]);
+        let mut inventory = Inventory ( HashMap::from([(wallet_key("ETH", &deltas::Host::Mainnet, "0xabc"), vec![fixtures::lot(1, 1.0, 1000.0)])]) );
+
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::Fifo, None, &[], &rules).unwrap();
+        assert!(calculation.owed.is_empty());
+        let rows: Vec<(f64, f64, u64)> = calculation.dispositions.0.iter().map(|d| (d.proceeds, d.cost_basis, d.acquired)).collect();
+        assert_eq!(rows, vec![(3000.0, 1000.0, 1)]);
+        assert!(inventory.0.values().all(|lots| lots.is_empty()));
+    }
+
+    fn token(timestamp: u64, direction: deltas::Direction, ilk: deltas::Ilk, asset: &str, contract: &str, qty: f64) -> deltas::Delta {
+        deltas::Delta { contract: Some(contract.to_string()), ..fixtures::delta(timestamp, direction, ilk, asset, qty) }
+    }
+
+    #[test]
+    fn spam_copying_a_symbol_leaves_the_real_token_alone() {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("USDC", fixtures::DAY, 1.0)]);
+        let linked = deltas::LinkedDeltas(vec![
+            deltas::DeltaGroup { ins: vec![token(t, deltas::Direction::In, deltas::Ilk::PhishingAttempt, "USDC", "0xfake", 500.0)], outs: Vec::new() },
+            deltas::DeltaGroup { ins: Vec::new(), outs: vec![token(t + 1, deltas::Direction::Out, deltas::Ilk::Payment, "USDC", "0xreal", 100.0)] },
+        ]);
+        let mut inventory = Inventory ( HashMap::from([("USDC".to_string(), vec![fixtures::lot(1, 100.0, 90.0)])]) );
+
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        let rows: Vec<(&str, f64, f64)> = calculation.dispositions.0.iter().map(|d| (d.asset.as_str(), d.proceeds, d.cost_basis)).collect();
+        assert_eq!(rows, vec![("USDC", 100.0, 90.0)]);
+        assert!(inventory.0["USDC"].is_empty());
+        assert_eq!(inventory.0["SPAM:mainnet:0xfake"][0].qty, 500.0);
+    }
+
+    #[test]
+    fn spam_from_last_year_goes_for_nothing() {
+        let drop = token(fixtures::noon("2022-03-01"), deltas::Direction::In, deltas::Ilk::PhishingAttempt, "SCAM", "0xscam", 1000.0);
+        let sweep = token(fixtures::noon("2022-04-01"), deltas::Direction::Out, deltas::Ilk::Payment, "SCAM", "0xscam", 1000.0);
+        let no_prices = fixtures::prices(&[]);
+
+        let mut inventory = Inventory ( HashMap::new() );
+        inventory.apply_deltas(&deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: vec![drop], outs: Vec::new() }]), "USD", &no_prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        let next = inventory.apply_deltas(&deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![sweep] }]), "USD", &no_prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        let rows: Vec<(&str, f64, f64)> = next.dispositions.0.iter().map(|d| (d.asset.as_str(), d.proceeds, d.cost_basis)).collect();
+        assert_eq!(rows, vec![("SPAM:mainnet:0xscam", 0.0, 0.0)]);
+        assert!(next.shortfalls.is_empty());
+    }
+
+    /// ETH bought long ago for 500, and a loan of `borrowed` ETH taken out
+    /// and repaid with `repaid` on `fixtures::DAY`, with ETH at 3000.
+    fn loan(borrowed: f64, repaid: f64) -> (Inventory, Calculation) {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0)]);
+        let linked = deltas::LinkedDeltas(vec![
+            deltas::DeltaGroup { ins: vec![fixtures::delta(t, deltas::Direction::In, deltas::Ilk::Loan, "ETH", borrowed)], outs: Vec::new() },
+            deltas::DeltaGroup { ins: Vec::new(), outs: vec![fixtures::delta(t + 1, deltas::Direction::Out, deltas::Ilk::Loan, "ETH", repaid)] },
+        ]);
+        let mut inventory = Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 1.0, 500.0)])]) );
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        (inventory, calculation)
+    }
+
+    #[test]
+    fn repayment_takes_back_the_borrowed_lots() {
+        let (inventory, calculation) = loan(1.0, 1.0);
+        assert!(calculation.dispositions.0.is_empty());
+        let left: Vec<(u64, f64, f64)> = inventory.0["ETH"].iter().map(|lot| (lot.timestamp, lot.qty, lot.cost)).collect();
+        assert_eq!(left, vec![(1, 1.0, 500.0)]);
+        let ledger: Vec<(loans::LoanRole, f64, f64)> = calculation.loans.0.iter().map(|e| (e.role, e.value, e.basis)).collect();
+        assert_eq!(ledger, vec![(loans::LoanRole::Borrow, 3000.0, 0.0), (loans::LoanRole::Repay, 3000.0, 3000.0)]);
+    }
+
+    #[test]
+    fn repaying_with_other_lots_disposes_of_them() {
+        let (inventory, calculation) = loan(1.0, 1.5);
+        let rows: Vec<(f64, f64, f64)> = calculation.dispositions.0.iter().map(|d| (d.qty, d.proceeds, d.cost_basis)).collect();
+        assert_eq!(rows, vec![(0.5, 1500.0, 250.0)]);
+        assert_eq!(calculation.summary.long_term_capital_gains, 1250.0);
+        assert_eq!(inventory.0["ETH"][0].qty, 0.5);
+        assert_eq!(calculation.loans.0[1].basis, 3250.0);
+    }
+
+    #[test]
+    fn interest_is_paid_by_disposing_of_its_asset() {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0)]);
+        let interest = fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::LoanInterestPayment, "ETH", 0.1);
+        let mut inventory = Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 1.0, 500.0)])]) );
+
+        let calculation = inventory.apply_deltas(&deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![interest] }]), "USD", &prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        let rows: Vec<(f64, f64, f64)> = calculation.dispositions.0.iter().map(|d| (d.qty, d.proceeds, d.cost_basis)).collect();
+        assert_eq!(rows, vec![(0.1, 300.0, 50.0)]);
+        assert_eq!(calculation.summary.loan_interest, 300.0);
+        assert_eq!(calculation.loans.0[0].basis, 50.0);
+    }
+
+    #[test]
+    fn collateral_is_held_apart_until_it_comes_back() {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0)]);
+        let vault = |timestamp, direction| fixtures::delta(timestamp, direction, deltas::Ilk::ChangeMakerVault, "ETH", 1.0);
+        let mut inventory = Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 2.0, 1000.0)])]) );
+        let key = loans::collateral_key(&vault(t, deltas::Direction::Out));
+        let lots = |inventory: &Inventory, key: &str| -> Vec<(u64, f64, f64)> {
+            inventory.0.get(key).map_or(Vec::new(), |lots| lots.iter().map(|lot| (lot.timestamp, lot.qty, lot.cost)).collect())
+        };
+
+        let posted = deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![vault(t, deltas::Direction::Out)] }]);
+        let calculation = inventory.apply_deltas(&posted, "USD", &prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        assert!(calculation.dispositions.0.is_empty());
+        assert_eq!(lots(&inventory, "ETH"), vec![(1, 1.0, 500.0)]);
+        assert_eq!(lots(&inventory, &key), vec![(1, 1.0, 500.0)]);
+        assert!(is_held_apart(&key));
+        assert_eq!(inventory.pooled().0.keys().filter(|key| !is_held_apart(key)).count(), 1);
+
+        let returned = deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: vec![vault(t + 1, deltas::Direction::In)], outs: Vec::new() }]);
+        let calculation = inventory.apply_deltas(&returned, "USD", &prices, InventoryMethod::Fifo, None, &[], &Rules::default()).unwrap();
+        assert!(calculation.dispositions.0.is_empty());
+        assert_eq!(lots(&inventory, "ETH"), vec![(1, 1.0, 500.0), (1, 1.0, 500.0)]);
+        assert!(lots(&inventory, &key).is_empty());
+        let ledger: Vec<(loans::LoanRole, f64)> = calculation.loans.0.iter().map(|e| (e.role, e.basis)).collect();
+        assert_eq!(ledger, vec![(loans::LoanRole::ReturnCollateral, 500.0)]);
+    }
+
+    #[test]
+    fn posting_more_collateral_than_held_follows_the_shortfall_policy() {
+        let t = fixtures::noon(fixtures::DAY);
+        let prices = fixtures::prices(&[("ETH", fixtures::DAY, 3000.0)]);
+        let post = fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::ChangeMakerVault, "ETH", 2.0);
+        let linked = deltas::LinkedDeltas(vec![deltas::DeltaGroup { ins: Vec::new(), outs: vec![post.clone()] }]);
+        let held = || Inventory ( HashMap::from([("ETH".to_string(), vec![fixtures::lot(1, 1.0, 500.0)])]) );
+
+        let error = Rules { shortfall_policy: shortfall::ShortfallPolicy::Error, ..Rules::default() };
+        assert!(held().apply_deltas(&linked, "USD", &prices, InventoryMethod::Fifo, None, &[], &error).is_err());
+
+        let zero_basis = Rules { shortfall_policy: shortfall::ShortfallPolicy::ZeroBasis, ..Rules::default() };
+        let mut inventory = held();
+        let calculation = inventory.apply_deltas(&linked, "USD", &prices, InventoryMethod::Fifo, None, &[], &zero_basis).unwrap();
+        assert_eq!(calculation.shortfalls.len(), 1);
+        let posted: f64 = inventory.0[&loans::collateral_key(&post)].iter().map(|lot| lot.qty).sum();
+        assert_eq!(posted, 2.0);
+    }
+
+    #[test]
+    fn migrated_lots_keep_their_provenance() {
+        let t = fixtures::noon(fixtures::DAY);
+        let rules = Rules {
+            lot_scope: LotScope::PerWallet,
+            migrations: migrations::MigrationTable(vec![migrations::Migration { from: "LEND".to_string(), to: "AAVE".to_string(), ratio: 0.01, effective: "2020-10-02".to_string() }]),
+            ..Rules::default()
+        };
+        let group = deltas::DeltaGroup {
+            ins: vec![fixtures::delta(t, deltas::Direction::In, deltas::Ilk::TokenMigration, "AAVE", 10.0)],
+            outs: vec![fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::TokenMigration, "LEND", 1000.0)],
+        };
+        let bought = Lot { host: Some(deltas::Host::Optimism), account: Some("0xbuyer".to_string()), ..fixtures::lot(1, 1000.0, 50.0) };
+        let mut inventory = Inventory ( HashMap::from([(rules.lot_key(&group.outs[0]), vec![bought])]) );
+
+        let calculation = inventory.apply_deltas(&deltas::LinkedDeltas(vec![group.clone()]), "USD", &fixtures::prices(&[]), InventoryMethod::Fifo, None, &[], &rules).unwrap();
+        assert!(calculation.unmatched_migrations.is_empty());
+        let migrated = &inventory.0[&rules.lot_key(&group.ins[0])];
+        assert_eq!(migrated.len(), 1);
+        assert_eq!((migrated[0].qty, migrated[0].cost), (10.0, 50.0));
+        assert_eq!((migrated[0].host.clone(), migrated[0].account.as_deref()), (Some(deltas::Host::Optimism), Some("0xbuyer")));
+    }
 }
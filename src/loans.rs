@@ -0,0 +1,145 @@
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use crate::deltas;
+use crate::symbols;
+use chrono::{TimeZone, Utc};
+
+
+/// The part a delta plays in a loan. Borrowing, repaying and moving
+/// collateral are not taxable; interest is an expense, paid by disposing
+/// of whatever asset it is paid in. Posted collateral is held apart from
+/// the wallets until it comes back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoanRole {
+    Borrow,
+    Repay,
+    PostCollateral,
+    ReturnCollateral,
+    Interest,
+}
+
+/// DAI drawn from or paid back to a Maker vault is the loan; anything else
+/// moving in or out of the vault is collateral.
+const MAKER_DEBT: &str = "DAI";
+
+/// The loan role of `delta`, if it has one.
+pub fn role(delta: &deltas::Delta) -> Option<LoanRole> {
+    let is_in = delta.direction == deltas::Direction::In;
+    match delta.ilk {
+        deltas::Ilk::Loan if is_in => Some(LoanRole::Borrow),
+        deltas::Ilk::Loan => Some(LoanRole::Repay),
+        deltas::Ilk::ChangeMakerVault if delta.asset == MAKER_DEBT && is_in => Some(LoanRole::Borrow),
+        deltas::Ilk::ChangeMakerVault if delta.asset == MAKER_DEBT => Some(LoanRole::Repay),
+        deltas::Ilk::ChangeMakerVault if is_in => Some(LoanRole::ReturnCollateral),
+        deltas::Ilk::ChangeMakerVault => Some(LoanRole::PostCollateral),
+        deltas::Ilk::LoanInterestPayment if !is_in => Some(LoanRole::Interest),
+        _ => None,
+    }
+}
+
+const COLLATERAL_PREFIX: &str = "COLLATERAL:";
+
+/// Inventory key for collateral posted with `delta`'s host, which holds its
+/// lots while they are out of our wallets.
+pub fn collateral_key(delta: &deltas::Delta) -> String {
+    format!("{}{}:{}", COLLATERAL_PREFIX, delta.host.to_string(), symbols::delta_tax_ticker(delta))
+}
+
+/// Whether an inventory key holds posted collateral.
+pub fn is_collateral_key(key: &str) -> bool {
+    key.starts_with(COLLATERAL_PREFIX)
+}
+
+/// One loan event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoanEntry {
+    pub role: LoanRole,
+    /// Tax ticker.
+    pub asset: String,
+    pub host: deltas::Host,
+    pub account: String,
+    pub identifier: String,
+    pub timestamp: u64,
+    pub qty: f64,
+    /// Fair market value in the quote currency.
+    pub value: f64,
+    /// Basis of the lots a repayment or interest payment used up, or that
+    /// collateral moved.
+    pub basis: f64,
+}
+
+impl LoanEntry {
+
+    pub fn new(role: LoanRole, delta: &deltas::Delta, value: f64, basis: f64) -> Self {
+        Self {
+            role,
+            asset: symbols::delta_tax_ticker(delta),
+            host: delta.host.clone(),
+            account: delta.account.clone(),
+            identifier: delta.identifier.clone(),
+            timestamp: delta.timestamp,
+            qty: delta.qty,
+            value,
+            basis,
+        }
+    }
+}
+
+/// Borrowings as liabilities, their repayments, collateral moves and
+/// interest, in the order they happened.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LoanLedger ( pub Vec<LoanEntry> );
+
+impl LoanLedger {
+
+    pub fn save (&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let json_string = serde_json::to_string(&self)?;
+        std::fs::write(path, &json_string)?;
+        Ok(())
+    }
+
+    /// Quantity still owed, or collateral still posted, per asset and host:
+    /// what was borrowed (posted) less what was repaid (returned).
+    pub fn outstanding(&self, owed: LoanRole, settled: LoanRole) -> Vec<(String, deltas::Host, f64)> {
+        let mut balances: Vec<(String, deltas::Host, f64)> = Vec::new();
+        for entry in self.0.iter().filter(|e| e.role == owed || e.role == settled) {
+            let qty = if entry.role == owed { entry.qty } else { -entry.qty };
+            match balances.iter_mut().find(|(asset, host, _)| *asset == entry.asset && *host == entry.host) {
+                Some(balance) => balance.2 += qty,
+                None => balances.push((entry.asset.clone(), entry.host.clone(), qty)),
+            }
+        }
+        balances
+    }
+
+    /// Interest paid, at fair market value when paid.
+    pub fn interest_paid(&self) -> f64 {
+        self.0.iter().filter(|e| e.role == LoanRole::Interest).map(|e| e.value).sum()
+    }
+
+    pub fn report(&self) -> String {
+        let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().format("%F").to_string();
+
+        let mut report = String::new();
+        report += "loan events (date, event, quantity, asset, host:account, value, basis used, identifier):\n";
+        for e in &self.0 {
+            report += &format!(" {} {:?} {:.8} {} {:?}:{} {:.2} {:.2} ({})\n", date(e.timestamp), e.role, e.qty, e.asset, e.host, e.account, e.value, e.basis, e.identifier);
+        }
+
+        report += "\nborrowed less repaid this year:\n";
+        for (asset, host, qty) in self.outstanding(LoanRole::Borrow, LoanRole::Repay) {
+            report += &format!(" {:.8} {} to {:?}\n", qty, asset, host);
+        }
+        report += "\ncollateral posted less returned this year:\n";
+        for (asset, host, qty) in self.outstanding(LoanRole::PostCollateral, LoanRole::ReturnCollateral) {
+            report += &format!(" {:.8} {} with {:?}\n", qty, asset, host);
+        }
+
+        report += "\ninterest paid, and the gain on the asset it was paid in:\n";
+        for e in self.0.iter().filter(|e| e.role == LoanRole::Interest) {
+            report += &format!(" {} {:.8} {}: interest {:.2}, gain {:.2}\n", date(e.timestamp), e.qty, e.asset, e.value, e.value - e.basis);
+        }
+        report += &format!(" total interest: {:.2}\n", self.interest_paid());
+        report
+    }
+}
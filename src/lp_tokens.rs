@@ -0,0 +1,69 @@
+use serde::{Serialize, Deserialize};
+
+
+/// Protocols whose liquidity is a fungible ERC-20 LP token. Adding
+/// liquidity disposes of the deposited tokens and acquires LP tokens at
+/// their value; removing it disposes of the LP tokens for the value of
+/// the underlyings returned, which start fresh lots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LpProtocol {
+    UniswapV1,
+    UniswapV2,
+    Sushi,
+    Aerodrome,
+    Stargate,
+}
+
+/// Stargate pool tokens, each `S*` followed by the pool's asset.
+const STARGATE_POOLS: &[&str] = &[
+    "S*USDC", "S*USDT", "S*DAI", "S*FRAX", "S*USDD", "S*SGETH", "S*sUSD", "S*LUSD", "S*MAI", "S*METIS", "S*BUSD",
+];
+
+/// The LP protocol of an asset, by the asset names the deltas use:
+/// `UNI-V1:{token}`, `UNI-V2:{token0}-{token1}` (or bare `UNI-V2`),
+/// `SLP:{token0}-{token1}` (or bare `SLP`), Aerodrome's
+/// `vAMM-{token0}/{token1}` and `sAMM-...`, and Stargate's `STGLP` and
+/// the pool tokens in `STARGATE_POOLS`.
+pub fn protocol(asset: &str) -> Option<LpProtocol> {
+    if asset.starts_with("UNI-V1:") {
+        Some(LpProtocol::UniswapV1)
+    } else if asset == "UNI-V2" || asset.starts_with("UNI-V2:") {
+        Some(LpProtocol::UniswapV2)
+    } else if asset == "SLP" || asset.starts_with("SLP:") {
+        Some(LpProtocol::Sushi)
+    } else if asset.starts_with("vAMM-") || asset.starts_with("sAMM-") {
+        Some(LpProtocol::Aerodrome)
+    } else if asset == "STGLP" || STARGATE_POOLS.contains(&asset) {
+        Some(LpProtocol::Stargate)
+    } else {
+        None
+    }
+}
+
+pub fn is_lp_token(asset: &str) -> bool {
+    protocol(asset).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_lp_token_names() {
+        assert_eq!(protocol("UNI-V1:MKR"), Some(LpProtocol::UniswapV1));
+        assert_eq!(protocol("UNI-V2"), Some(LpProtocol::UniswapV2));
+        assert_eq!(protocol("UNI-V2:WETH-USDC"), Some(LpProtocol::UniswapV2));
+        assert_eq!(protocol("SLP"), Some(LpProtocol::Sushi));
+        assert_eq!(protocol("SLP:WETH-SUSHI"), Some(LpProtocol::Sushi));
+        assert_eq!(protocol("vAMM-WETH/USDC"), Some(LpProtocol::Aerodrome));
+        assert_eq!(protocol("STGLP"), Some(LpProtocol::Stargate));
+        assert_eq!(protocol("S*USDC"), Some(LpProtocol::Stargate));
+    }
+
+    #[test]
+    fn ignores_tokens_that_only_share_a_prefix() {
+        for asset in ["SLPX", "SLPT", "S*", "S*PEPE", "UNI-V2X", "UNI", "WETH"] {
+            assert_eq!(protocol(asset), None, "{}", asset);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Serialize, Deserialize};
+use chrono::{NaiveDate, TimeZone, Utc};
+use crate::deltas;
+use crate::inventory;
+use crate::symbols;
+
+
+/// Relative slack when matching a migration's receiving quantity to the
+/// sent quantity times the ratio.
+const QTY_TOLERANCE: f64 = 0.000001;
+
+/// One token migration or rename: from its effective date, each `from`
+/// becomes `ratio` of `to`, keeping its acquisition date and basis.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Migration {
+    /// Tax ticker migrated from.
+    pub from: String,
+    /// Tax ticker migrated to.
+    pub to: String,
+    pub ratio: f64,
+    /// `YYYY-MM-DD`, in the holding period's time zone.
+    pub effective: String,
+}
+
+impl Migration {
+    /// Whether this migration covers sending `out` and receiving `receiving`.
+    fn matches(&self, out: &deltas::Delta, receiving: &deltas::Delta, holding_period: &inventory::HoldingPeriod) -> bool {
+        let date = holding_period.date(out.timestamp);
+        let effective = NaiveDate::parse_from_str(&self.effective, "%F").is_ok_and(|effective| effective <= date);
+        let expected = out.qty * self.ratio;
+        effective
+            && symbols::delta_tax_ticker(out) == self.from
+            && symbols::delta_tax_ticker(receiving) == self.to
+            && (receiving.qty - expected).abs() <= QTY_TOLERANCE * expected.abs().max(1.0)
+    }
+}
+
+/// The migrations `Inventory::apply_deltas` moves lots for.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MigrationTable ( pub Vec<Migration> );
+
+impl MigrationTable {
+
+    /// Pairs each migrating Out in `group` with the In it became, under
+    /// the table entry that covers both. Deltas left over are unmatched.
+    /// Effective dates are read in `holding_period`'s time zone.
+    pub fn pair<'a, 'b>(&'b self, group: &'a deltas::DeltaGroup, holding_period: &inventory::HoldingPeriod) -> (Vec<(&'a deltas::Delta, &'a deltas::Delta, &'b Migration)>, Vec<Unmatched>) {
+        let mut pairs = Vec::new();
+        let mut unmatched = Vec::new();
+        let mut used = vec![false; group.ins.len()];
+
+        for out in group.outs.iter().filter(|d| is_migration(&d.ilk)) {
+            let found = group.ins.iter().enumerate()
+                .filter(|(i, d)| !used[*i] && is_migration(&d.ilk))
+                .find_map(|(i, receiving)| self.0.iter().find(|m| m.matches(out, receiving, holding_period)).map(|m| (i, receiving, m)));
+            match found {
+                Some((i, receiving, migration)) => {
+                    used[i] = true;
+                    pairs.push((out, receiving, migration));
+                },
+                None => unmatched.push(Unmatched { delta: out.clone(), reason: "no receiving delta under a migration in the table".to_string() }),
+            }
+        }
+        for (i, receiving) in group.ins.iter().enumerate() {
+            if !used[i] && is_migration(&receiving.ilk) {
+                unmatched.push(Unmatched { delta: receiving.clone(), reason: "no sending delta under a migration in the table".to_string() });
+            }
+        }
+        (pairs, unmatched)
+    }
+}
+
+/// Token migrations and renames, which carry lots rather than dispose.
+pub fn is_migration(ilk: &deltas::Ilk) -> bool {
+    *ilk == deltas::Ilk::TokenMigration || *ilk == deltas::Ilk::AssetRename
+}
+
+/// A migration delta no table entry paired, left out of the inventory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Unmatched {
+    pub delta: deltas::Delta,
+    pub reason: String,
+}
+
+/// The unmatched migration deltas as a CSV.
+pub fn report(unmatched: &[Unmatched]) -> String {
+    let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+    let mut csv = "date,direction,ilk,asset,quantity,host,account,identifier,reason\n".to_string();
+    for u in unmatched {
+        csv += &format!(
+            "{},{:?},{:?},{},{:.8},{:?},{},{},{}\n",
+            date(u.delta.timestamp),
+            u.delta.direction,
+            u.delta.ilk,
+            u.delta.asset,
+            u.delta.qty,
+            u.delta.host,
+            u.delta.account,
+            u.delta.identifier,
+            u.reason,
+        );
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn table(effective: &str) -> MigrationTable {
+        MigrationTable(vec![Migration { from: "LEND".to_string(), to: "AAVE".to_string(), ratio: 0.01, effective: effective.to_string() }])
+    }
+
+    /// 1000 LEND out for `received` AAVE in, at 2024-03-01T03:00Z, still
+    /// Feb 29 in New York.
+    fn migration(received: f64) -> deltas::DeltaGroup {
+        let t = fixtures::millis_in(chrono_tz::UTC, "2024-03-01 03:00");
+        deltas::DeltaGroup {
+            ins: vec![fixtures::delta(t, deltas::Direction::In, deltas::Ilk::TokenMigration, "AAVE", received)],
+            outs: vec![fixtures::delta(t, deltas::Direction::Out, deltas::Ilk::TokenMigration, "LEND", 1000.0)],
+        }
+    }
+
+    #[test]
+    fn pairs_out_with_its_receiving_in() {
+        let group = migration(10.0);
+        let table = table("2024-02-01");
+        let (pairs, unmatched) = table.pair(&group, &inventory::HoldingPeriod::default());
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].0.asset, "LEND");
+        assert_eq!(pairs[0].1.asset, "AAVE");
+        assert!(unmatched.is_empty());
+    }
+
+    #[test]
+    fn wrong_ratio_leaves_both_sides_unmatched() {
+        let group = migration(11.0);
+        let table = table("2024-02-01");
+        let (pairs, unmatched) = table.pair(&group, &inventory::HoldingPeriod::default());
+        assert!(pairs.is_empty());
+        assert_eq!(unmatched.len(), 2);
+    }
+
+    #[test]
+    fn effective_date_is_in_the_holding_period_time_zone() {
+        let group = migration(10.0);
+        let utc = inventory::HoldingPeriod::default();
+        let new_york = inventory::HoldingPeriod { time_zone: chrono_tz::America::New_York };
+        assert_eq!(table("2024-03-01").pair(&group, &utc).0.len(), 1);
+        assert_eq!(table("2024-03-01").pair(&group, &new_york).0.len(), 0);
+    }
+}
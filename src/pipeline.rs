@@ -0,0 +1,644 @@
+use std::collections::HashMap;
+use std::error::Error;
+use crate::allocation;
+use crate::config;
+use crate::deltas;
+use crate::disposition;
+use crate::form_1099da;
+use crate::form_8949;
+use crate::inventory;
+use crate::migrations;
+use crate::position_assets;
+use crate::positions;
+use crate::prices;
+use crate::shortfall;
+use crate::specific_id;
+use crate::symbols;
+use chrono::{NaiveDate, TimeZone, Utc};
+
+const BALANCE_TOLERANCE: f64 = 0.000000001;
+
+
+/// The link -> price -> calculate -> reconcile steps for one tax year.
+/// Everything year specific comes from the `YearConfig`; files are read
+/// from and written to `{data_root}/{year}/`.
+pub struct Pipeline {
+    pub config: config::YearConfig,
+    pub data_root: String,
+    pub quote_currency: String,
+}
+
+impl Pipeline {
+
+    pub fn new(config: config::YearConfig, data_root: &str, quote_currency: &str) -> Self {
+        Self {
+            config,
+            data_root: data_root.to_string(),
+            quote_currency: quote_currency.to_string(),
+        }
+    }
+
+    pub fn year_path(&self, year: i32, file: &str) -> String {
+        format!("{}/{}/{}", self.data_root, year, file)
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.year_path(self.config.year, file)
+    }
+
+    fn prices_path(&self) -> String {
+        self.path(&format!("prices_{}.json", self.quote_currency))
+    }
+
+    pub fn lot_selections_path(&self) -> String {
+        self.path("lot_selections_us.json")
+    }
+
+    /// Where a `mintax` run records its choices. Kept apart from
+    /// `lot_selections_path` so a run never overwrites a hand-made
+    /// selection file; copy it there to file with `--method specific`.
+    pub fn mintax_selections_path(&self) -> String {
+        self.path("lot_selections_mintax_us.json")
+    }
+
+    /// `method` with any year-specific parameters filled in from the config.
+    fn resolve_method(&self, method: inventory::InventoryMethod) -> inventory::InventoryMethod {
+        match method {
+            inventory::InventoryMethod::MinTax(_) => inventory::InventoryMethod::MinTax(self.config.tax_rates),
+            _ => method,
+        }
+    }
+
+    /// The year's lot selection file, if `method` needs one.
+    fn lot_selections(&self, method: inventory::InventoryMethod) -> Result<Option<specific_id::LotSelections>, Box<dyn Error>> {
+        match method {
+            inventory::InventoryMethod::SpecificId => Ok(Some(specific_id::LotSelections::load(&self.lot_selections_path())?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// What last year's shortfalls left for this year's acquisitions to make
+    /// good, when the year carries on from last year's end inventory.
+    fn owed_shortfalls(&self) -> Result<Vec<shortfall::Owed>, Box<dyn Error>> {
+        let path = self.year_path(self.config.year - 1, "owed_shortfalls_us.json");
+        match self.config.opening {
+            config::Opening::ZeroCost => Ok(Vec::new()),
+            _ if std::path::Path::new(&path).exists() => shortfall::Owed::load_all(&path),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    pub fn save_linked_deltas(&self) -> Result<(), Box<dyn Error>> {
+        println!("loading unlinked deltas...");
+        let deltas = deltas::Deltas::load(&self.path("unlinked_deltas.json"))?;
+        println!("loaded {} deltas, linking...", deltas.0.len());
+        let linked = deltas.link();
+        println!("linked into {} groups, saving...", linked.0.len());
+        linked.save(&self.path("linked_deltas.json"))?;
+        println!("saved, checking...");
+        self.check_linked_deltas()
+    }
+
+    pub fn check_linked_deltas(&self) -> Result<(), Box<dyn Error>> {
+        let linked = deltas::LinkedDeltas::load(&self.path("linked_deltas.json"))?;
+
+        let mut total = 0;
+        let mut unlinked = 0;
+        for group in &linked.0 {
+            for delta in &group.ins {
+                if self.config.needs_link(delta) {
+                    total += 1;
+                    if group.outs.is_empty() && group.ins.len() == 1 {
+                        println!("needs link: {:#?}", delta);
+                        unlinked += 1;
+                    }
+                }
+            }
+        }
+        println!("required: {} unlinked of {} needed", unlinked, total);
+
+        linked.disposition_links();
+        Ok(())
+    }
+
+    /// Saves the year's prices, and its CAD prices too when the year has a
+    /// Canadian return.
+    pub fn save_prices(&self) -> Result<(), Box<dyn Error>> {
+        let deltas = deltas::Deltas::load(&self.path("unlinked_deltas.json"))?;
+        self.fetch_prices(&deltas, &self.config.prices)?.save(&self.prices_path())?;
+
+        if let Some(canada) = &self.config.canada {
+            if let config::PriceSource::Coingecko { .. } = canada.prices.source {
+                return Err("coingecko prices are in USD, the Canadian return needs a CAD source".into());
+            }
+            self.fetch_prices(&deltas, &canada.prices)?.save(&self.path("prices_CAD.json"))?;
+        }
+        Ok(())
+    }
+
+    /// Prices for every asset the deltas use, from `price_config`'s sources.
+    fn fetch_prices(&self, deltas: &deltas::Deltas, price_config: &config::PriceConfig) -> Result<prices::Prices, Box<dyn Error>> {
+        let mut spam = deltas.spam_ids();
+        spam.extend(self.carried_spam()?);
+        let mut used_assets = if price_config.tax_tickers {
+            symbols::batch_onchain_to_tax_ticker(&deltas.used_assets(&spam))
+        } else {
+            deltas.used_assets(&spam)
+        };
+        for asset in &price_config.extra_assets {
+            if !used_assets.contains(asset) {
+                used_assets.push(asset.clone());
+            }
+        }
+        println!("used_assets: {:?}", used_assets);
+
+        let mut prices = match &price_config.source {
+            config::PriceSource::Dir { path } => prices::Prices::load_dir(path, &used_assets)?,
+            config::PriceSource::Coingecko { api_key_path, delay_millis } => {
+                let api_key = std::fs::read_to_string(api_key_path)?.trim().to_string();
+                let from = Utc.with_ymd_and_hms(self.config.year, 1, 1, 0, 0, 0).unwrap().timestamp();
+                let to = Utc.with_ymd_and_hms(self.config.year + 1, 1, 1, 0, 0, 0).unwrap().timestamp();
+                prices::Prices::fetch_coingecko(&used_assets, from, to, &api_key, *delay_millis)?
+            }
+        };
+
+        if let Some(patch) = &price_config.patch {
+            let other_prices = prices::Prices::load_dir(&patch.path, &used_assets)?;
+            let from = NaiveDate::parse_from_str(&patch.from, "%F")?;
+            let to = NaiveDate::parse_from_str(&patch.to, "%F")?;
+            prices.patch(&other_prices, from.and_hms_opt(0, 0, 0).unwrap().and_utc(), to.and_hms_opt(0, 0, 0).unwrap().and_utc());
+        }
+        Ok(prices)
+    }
+
+    /// Spam ids held at the end of last year, when this year carries on
+    /// from it and it has been calculated.
+    fn carried_spam(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let path = self.year_path(self.config.year - 1, "end_inventory_us.json");
+        if matches!(self.config.opening, config::Opening::ZeroCost) || !std::path::Path::new(&path).exists() {
+            return Ok(Vec::new())
+        }
+        let inventory = inventory::Inventory::load(&path)?;
+        Ok(inventory.0.into_keys().filter(|key| deltas::is_spam_id(key)).collect())
+    }
+
+    /// The inventory at Jan 1, either built from the opening balances or
+    /// carried over from last year and reconciled against them. `carried`
+    /// is last year's end inventory when it is already in memory; otherwise
+    /// it is read from last year's `end_inventory_us.json`.
+    pub fn initial_inventory(&self, carried: Option<inventory::Inventory>) -> Result<inventory::Inventory, Box<dyn Error>> {
+        let opening_balances = load_balances(&self.path(&self.config.opening_balances))?;
+
+        match self.config.opening {
+            config::Opening::ZeroCost => {
+                let ts = Utc.with_ymd_and_hms(self.config.year, 1, 1, 0, 0, 0).unwrap().timestamp_millis();
+                let mut initial_inventory = inventory::Inventory::initiate_zero_cost(&opening_balances, ts as u64);
+                for (name, aliases) in &self.config.opening_aliases {
+                    if !initial_inventory.0.contains_key(name) {
+                        initial_inventory.add_asset(name);
+                    }
+                    for alias in aliases {
+                        if alias != name && initial_inventory.0.contains_key(alias) {
+                            initial_inventory.consolidate_alias(name, alias);
+                        }
+                    }
+                }
+                Ok(initial_inventory)
+            },
+            config::Opening::Carried => {
+                self.carried_inventory(carried, &opening_balances)
+            },
+            config::Opening::Allocated => {
+                let pooled = self.carried_inventory(carried, &opening_balances)?;
+                let allocation = allocation::Allocation::load(&self.path("allocation_us.json"))?;
+                allocation.check_against(&pooled.pooled())?;
+                Ok(allocation.inventory())
+            },
+        }
+    }
+
+    /// Last year's end inventory, reconciled against this year's opening
+    /// balances.
+    fn carried_inventory(&self, carried: Option<inventory::Inventory>, opening_balances: &HashMap<String, f64>) -> Result<inventory::Inventory, Box<dyn Error>> {
+        let initial_inventory = match carried {
+            Some(inventory) => inventory,
+            None => inventory::Inventory::load(&self.year_path(self.config.year - 1, "end_inventory_us.json"))?,
+        };
+        reconcile(&initial_inventory.pooled(), opening_balances, |asset| self.config.opening_names(asset))?;
+        Ok(initial_inventory)
+    }
+
+    /// Splits last year's pooled end inventory over this year's wallets,
+    /// saving the allocation the `Allocated` opening reads and a CSV of it
+    /// to attach to the return.
+    pub fn allocate(&self) -> Result<(), Box<dyn Error>> {
+        let allocation_config = self.config.allocation.as_ref()
+            .ok_or_else(|| format!("no allocation configured for {}", self.config.year))?;
+
+        let opening_balances = load_balances(&self.path(&self.config.opening_balances))?;
+        let pooled = self.carried_inventory(None, &opening_balances)?.pooled();
+        let balances = allocation::WalletBalance::load_all(&self.path(&allocation_config.wallet_balances))?;
+        let rule = match &allocation_config.mapping {
+            Some(file) => allocation::AllocationRule::Mapping(allocation::LotAssignment::load_all(&self.path(file))?),
+            None => allocation::AllocationRule::OldestToLargest,
+        };
+
+        let effective = Utc.with_ymd_and_hms(self.config.year, 1, 1, 0, 0, 0).unwrap().timestamp_millis() as u64;
+        let allocation = allocation::Allocation::allocate(&pooled, &balances, &rule, effective)?;
+
+        for balance in &allocation.uncovered {
+            println!("uncovered balance: {:?} {} {} {}", balance.host, balance.account, balance.asset, balance.qty);
+        }
+
+        allocation.save(&self.path("allocation_us.json"))?;
+        std::fs::write(self.path("allocation_us.csv"), allocation.to_csv())?;
+        Ok(())
+    }
+
+    pub fn calculate(&self, method: inventory::InventoryMethod) -> Result<inventory::CapitalGainsSummary, Box<dyn Error>> {
+        let inventory = self.initial_inventory(None)?;
+        let (summary, _) = self.calculate_from(inventory, method)?;
+        Ok(summary)
+    }
+
+    /// Applies the year's linked deltas to `inventory`, saves the year's
+    /// outputs and returns the summary along with the end inventory.
+    pub fn calculate_from(&self, mut inventory: inventory::Inventory, method: inventory::InventoryMethod) -> Result<(inventory::CapitalGainsSummary, inventory::Inventory), Box<dyn Error>> {
+        for asset in &self.config.added_assets {
+            inventory.add_asset(asset);
+        }
+        let prices = prices::Prices::load(&self.prices_path())?;
+        let mut linked = deltas::LinkedDeltas::load(&self.path("linked_deltas.json"))?;
+        linked.reassign_quote_fee_links(&self.quote_currency);
+        let method = self.resolve_method(method);
+        let selections = self.lot_selections(method)?;
+        let owed = self.owed_shortfalls()?;
+
+        let inventory::Calculation { summary, dispositions, selections: made_selections, shortfalls, loans, positions, unmatched_migrations, owed } = inventory.apply_deltas(&linked, &self.quote_currency, &prices, method, selections.as_ref(), &owed, &self.config.rules)?;
+
+        std::fs::write(self.path("shortfalls_us.csv"), shortfall::report(&shortfalls))?;
+        if !shortfalls.is_empty() {
+            println!("{} disposals or transfers of more than was held, see shortfalls_us.csv", shortfalls.len());
+        }
+        shortfall::Owed::save_all(&owed, &self.path("owed_shortfalls_us.json"))?;
+
+        // The optimizer's choices are recorded as a specific identification
+        // file, so the return can be reproduced with `--method specific`.
+        if let inventory::InventoryMethod::MinTax(_) = method {
+            made_selections.save(&self.mintax_selections_path())?;
+            println!("lot choices saved to {}, copy to {} to file with them", self.mintax_selections_path(), self.lot_selections_path());
+        }
+
+        inventory.save(&self.path("end_inventory_us.json"))?;
+        summary.save(&self.path("summary_us.json"))?;
+        dispositions.save(&self.path("all_dispositions_us.json"))?;
+        std::fs::write(self.path("all_dispositions_us.csv"), dispositions.to_csv(&self.quote_currency))?;
+        loans.save(&self.path("loans_us.json"))?;
+        std::fs::write(self.path("loans_us.txt"), loans.report())?;
+        std::fs::write(self.path("positions_us.txt"), positions.report())?;
+        std::fs::write(self.path("unmatched_migrations_us.csv"), migrations::report(&unmatched_migrations))?;
+        if !unmatched_migrations.is_empty() {
+            println!("{} migration deltas no migration covers, see unmatched_migrations_us.csv", unmatched_migrations.len());
+        }
+
+        self.check_end_inventory()?;
+        self.report()?;
+        self.export_form_8949()?;
+
+        Ok((summary, inventory))
+    }
+
+    /// Runs every inventory method (including the tax optimizer, plus
+    /// specific identification when the year has a lot selection file) against the same opening inventory,
+    /// deltas and prices, and prints their results side by side along with
+    /// the end-of-year basis of every asset whose basis depends on the
+    /// method. Nothing is saved.
+    pub fn compare_methods(&self) -> Result<(), Box<dyn Error>> {
+        let mut opening = self.initial_inventory(None)?;
+        for asset in &self.config.added_assets {
+            opening.add_asset(asset);
+        }
+        let prices = prices::Prices::load(&self.prices_path())?;
+        let mut linked = deltas::LinkedDeltas::load(&self.path("linked_deltas.json"))?;
+        linked.reassign_quote_fee_links(&self.quote_currency);
+
+        let mut methods = inventory::InventoryMethod::ALL.to_vec();
+        methods.push(self.resolve_method(inventory::InventoryMethod::MinTax(Default::default())));
+        if std::path::Path::new(&self.lot_selections_path()).exists() {
+            methods.push(inventory::InventoryMethod::SpecificId);
+        }
+
+        let owed = self.owed_shortfalls()?;
+        let mut results = Vec::new();
+        for method in methods {
+            let selections = self.lot_selections(method)?;
+            let mut inventory = opening.clone();
+            let calculation = inventory.apply_deltas(&linked, &self.quote_currency, &prices, method, selections.as_ref(), &owed, &self.config.rules)?;
+            results.push((calculation.summary, inventory.cost_basis()));
+        }
+
+        let mut table = String::new();
+        table += &format!("{} method comparison, all values in {}\n", self.config.year, self.quote_currency);
+        table += "\n";
+        table += &format!("{:<16}{:>20}{:>20}{:>20}{:>20}\n", "method", "income", "short term", "long term", "end basis");
+        for (summary, basis) in &results {
+            let end_basis: f64 = basis.values().sum();
+            table += &format!(
+                "{:<16}{:>20.8}{:>20.8}{:>20.8}{:>20.8}\n",
+                summary.inventory_method,
+                summary.income,
+                summary.short_term_capital_gains,
+                summary.long_term_capital_gains,
+                end_basis,
+            );
+        }
+        table += "\n";
+
+        let mut assets: Vec<&String> = results.iter().flat_map(|(_, basis)| basis.keys()).collect();
+        assets.sort();
+        assets.dedup();
+
+        table += "end basis by asset (where methods differ):\n";
+        table += &format!("{:<24}", "asset");
+        for (summary, _) in &results {
+            table += &format!("{:>20}", summary.inventory_method);
+        }
+        table += "\n";
+        for asset in assets {
+            let bases: Vec<f64> = results.iter()
+                .map(|(_, basis)| basis.get(asset).copied().unwrap_or(0.0))
+                .collect();
+            let min = bases.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = bases.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            if max - min < BALANCE_TOLERANCE {
+                continue
+            }
+            table += &format!("{:<24}", asset);
+            for b in bases {
+                table += &format!("{:>20.8}", b);
+            }
+            table += "\n";
+        }
+
+        println!("{}", table);
+        Ok(())
+    }
+
+    /// Writes `capital_gains_report_us.txt` from the summary saved by the
+    /// last `calculate`.
+    pub fn report(&self) -> Result<(), Box<dyn Error>> {
+        let summary = inventory::CapitalGainsSummary::load(&self.path("summary_us.json"))?;
+
+        let year = self.config.year;
+        let mut report = String::new();
+        report += "\n";
+        report += &format!("all values in {}\n", self.quote_currency);
+        report += &format!("{}\n", self.config.price_description);
+        report += "\n";
+
+        report += &format!("{} cryptocurrency income:\n", year);
+        for (label, amount) in summary.income_by_category.lines() {
+            report += &format!(" {}: {:.8}\n", label, amount);
+        }
+        report += &format!(" total income: {:.8}\n", summary.income);
+        report += "\n";
+        report += &format!("{} fees:\n", year);
+        report += &summary.fees.report();
+        report += "\n";
+        report += &format!("{} loan interest paid (see loans_us.txt):\n", year);
+        report += &format!(" interest: {:.8}\n", summary.loan_interest);
+        report += "\n";
+        report += &format!("{} theft and scam losses (not capital losses):\n", year);
+        report += &disposition::Dispositions::load(&self.path("all_dispositions_us.json"))?.casualty_report();
+        report += "\n";
+        report += &format!("{} cryptocurrency capital_gains:\n", year);
+        report += &format!(" inventory method: {:.8}\n", summary.inventory_method);
+        report += &format!(" short term capital gains: {:.8}\n", summary.short_term_capital_gains);
+        report += &format!(" long term capital gains: {:.8}\n", summary.long_term_capital_gains);
+        report += "\n";
+        println!("{}", report);
+
+        std::fs::write(self.path("capital_gains_report_us.txt"), report)?;
+        Ok(())
+    }
+
+    /// Matches the year's 1099-DA sales against the last calculation's
+    /// dispositions and writes `1099da_reconciliation_us.txt`. `None` when
+    /// the year has no broker files.
+    pub fn reconcile_1099da(&self) -> Result<Option<form_1099da::Reconciliation>, Box<dyn Error>> {
+        if self.config.form_1099da.is_empty() {
+            return Ok(None)
+        }
+        let dispositions = disposition::Dispositions::load(&self.path("all_dispositions_us.json"))?;
+        let mut reported = Vec::new();
+        for file in &self.config.form_1099da {
+            reported.extend(form_1099da::ReportedSale::load_all(&self.path(file))?);
+        }
+
+        let reconciliation = form_1099da::Reconciliation::reconcile(&dispositions, &reported, self.config.rules.holding_period.time_zone);
+        let report = reconciliation.report();
+        println!("{}", report);
+        std::fs::write(self.path("1099da_reconciliation_us.txt"), report)?;
+        Ok(Some(reconciliation))
+    }
+
+    /// Writes `form_8949_us.csv` and `schedule_d_us.txt` from the last
+    /// calculation's dispositions, adjusted by the 1099-DA reconciliation
+    /// when the year has broker files.
+    pub fn export_form_8949(&self) -> Result<(), Box<dyn Error>> {
+        let dispositions = disposition::Dispositions::load(&self.path("all_dispositions_us.json"))?;
+        let reconciliation = self.reconcile_1099da()?;
+        let form = form_8949::Form8949::from_dispositions(self.config.year, &dispositions, self.config.rules.holding_period.time_zone, reconciliation.as_ref());
+        let schedule_d = form.schedule_d();
+        println!("{}", schedule_d);
+
+        std::fs::write(self.path("form_8949_us.csv"), form.to_csv())?;
+        std::fs::write(self.path("schedule_d_us.txt"), schedule_d)?;
+        Ok(())
+    }
+
+    /// The Canadian return for the part of the year before departure: CAD
+    /// income and capital gains on pooled cost, then the deemed disposition
+    /// of everything still held at the previous day's close. Writes
+    /// `all_dispositions_canada.csv` and `capital_gains_report_canada.txt`.
+    /// Pooled holdings aren't carried between years, so the year must open
+    /// at zero cost.
+    pub fn calculate_canada(&self) -> Result<(), Box<dyn Error>> {
+        let canada = self.config.canada.as_ref()
+            .ok_or_else(|| format!("no Canadian return configured for {}", self.config.year))?;
+        if !matches!(self.config.opening, config::Opening::ZeroCost) {
+            return Err(format!("the Canadian return for {} needs a ZeroCost opening", self.config.year).into());
+        }
+        let departure = NaiveDate::parse_from_str(&canada.departure, "%F")?;
+        let departure_ts = departure.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis() as u64;
+        let last_day = departure.pred_opt().unwrap();
+
+        let opening_balances = load_balances(&self.path(&self.config.opening_balances))?;
+        let mut holdings = inventory::ConsolidatedInventory::initiate_zero_cost(&opening_balances);
+        for (name, aliases) in &self.config.opening_aliases {
+            for alias in aliases.iter().filter(|alias| *alias != name) {
+                holdings.consolidate_alias(name, alias);
+            }
+        }
+        let prices = prices::Prices::load(&self.path("prices_CAD.json"))?;
+        let close_prices = prices::Prices::load(&canada.close_prices)?;
+
+        let deltas = deltas::Deltas::load(&self.path("unlinked_deltas.json"))?;
+        let linked = deltas::Deltas(deltas.0.into_iter().filter(|d| d.timestamp < departure_ts).collect()).link();
+        let (summary, dispositions) = holdings.apply_deltas(&linked, "CAD", &prices)?;
+
+        let mut report = String::new();
+        report += "\n";
+        report += "all values in CAD\n";
+        report += &format!("{}\n", canada.price_description);
+        report += &format!("day close prices for {} used for value at deemed dispositions\n", last_day);
+        report += "\n";
+
+        report += &format!("{}-01-01 to {} cryptocurrency income (\"airdrops\"):\n", self.config.year, last_day);
+        report += &format!(" income: {:.8}\n", summary.income);
+        report += "\n";
+
+        report += &format!("{}-01-01 to {} capital gains (not including deemed dispositions):\n", self.config.year, last_day);
+        report += &format!(" capital gains: {:.8}\n", summary.capital_gains);
+        report += "\n";
+
+        report += &format!("holdings on {} EOD:\n", last_day);
+        let mut assets: Vec<&String> = holdings.0.keys().collect();
+        assets.sort();
+        let mut total_cost = 0.0;
+        let mut total_value = 0.0;
+        for asset in assets {
+            let holding = &holdings.0[asset];
+            if holding.qty < 0.00000001 || asset == "USD" {
+                continue
+            }
+            let value = holding.qty * close_prices.price_at_millis(asset, departure_ts - 1)?;
+            report += &format!(" {}:\n", asset);
+            report += &format!("  balance: {:.8}\n", holding.qty);
+            report += &format!("  cost basis: {:.8}\n", holding.cost);
+            report += &format!("  market value: {:.8}\n", value);
+            total_cost += holding.cost;
+            total_value += value;
+        }
+        report += "\n";
+        report += " ALL:\n";
+        report += &format!("  cost basis: {:.8}\n", total_cost);
+        report += &format!("  market value: {:.8}\n", total_value);
+        report += &format!("  capital gains: {:.8}\n", total_value - total_cost);
+        report += "\n";
+
+        report += "summary:\n";
+        report += &format!(" cryptocurrency income: {:.8}\n", summary.income);
+        report += &format!(" capital gains (including deemed dispositions): {:.8}\n", summary.capital_gains + (total_value - total_cost));
+        println!("{}", report);
+
+        std::fs::write(self.path("all_dispositions_canada.csv"), dispositions)?;
+        std::fs::write(self.path("capital_gains_report_canada.txt"), report)?;
+        Ok(())
+    }
+
+    /// Reconciles the saved end inventory against the Dec 31 balances.
+    /// A no-op for years without closing balances.
+    pub fn check_end_inventory(&self) -> Result<(), Box<dyn Error>> {
+        let closing_balances = match &self.config.closing_balances {
+            Some(file) => load_balances(&self.path(file))?,
+            None => {
+                println!("no closing balances configured for {}", self.config.year);
+                return Ok(())
+            }
+        };
+
+        let end_inventory_us = inventory::Inventory::load(&self.path("end_inventory_us.json"))?;
+        reconcile(&end_inventory_us.pooled(), &closing_balances, |asset| self.config.closing_names(asset))
+    }
+}
+
+
+/// Recalculates every year from `first_year` through `last_year` in order,
+/// handing each year's end inventory to the next as its opening inventory,
+/// along with the shortfalls it still owes. Opening balances are
+/// reconciled at every year boundary, so a change to an early year can't
+/// leave later years silently stale, and a year with shortfalls under
+/// `ShortfallPolicy::Error` stops the chain.
+pub fn run_chain(config_dir: &str, data_root: &str, quote_currency: &str, first_year: i32, last_year: i32, method: inventory::InventoryMethod) -> Result<Vec<(i32, inventory::CapitalGainsSummary)>, Box<dyn Error>> {
+    if first_year > last_year {
+        return Err(format!("chain runs forwards: {} is after {}", first_year, last_year).into());
+    }
+
+    let mut summaries = Vec::new();
+    let mut carried = None;
+
+    for year in first_year..=last_year {
+        println!("==== {} ====", year);
+        let config = config::YearConfig::load(&format!("{}/{}.json", config_dir, year))?;
+        let pipeline = Pipeline::new(config, data_root, quote_currency);
+
+        let opening = pipeline.initial_inventory(carried.take())
+            .map_err(|e| format!("{}: opening inventory: {}", year, e))?;
+        let (summary, end) = pipeline.calculate_from(opening, method)
+            .map_err(|e| format!("{}: {}", year, e))?;
+
+        summaries.push((year, summary));
+        carried = Some(end);
+    }
+
+    let mut table = "year,inventory_method,income,short_term_capital_gains,long_term_capital_gains\n".to_string();
+    for (year, summary) in &summaries {
+        table += &format!(
+            "{},{},{:.8},{:.8},{:.8}\n",
+            year,
+            summary.inventory_method,
+            summary.income,
+            summary.short_term_capital_gains,
+            summary.long_term_capital_gains,
+        );
+    }
+    println!("{}", table);
+    std::fs::write(format!("{}/chain_summary_us.csv", data_root), table)?;
+
+    Ok(summaries)
+}
+
+
+pub fn load_balances(path: &str) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+    let data = std::fs::read_to_string(path)?;
+    let balances: HashMap<String, f64> = serde_json::from_str(&data)?;
+    Ok(balances)
+}
+
+/// Errors if the inventory holds more of any asset than the balances say
+/// we own. `names` maps a tax ticker to the balance names summed for it.
+fn reconcile<F>(inventory: &inventory::Inventory, balances: &HashMap<String, f64>, names: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(&str) -> Vec<String>,
+{
+    // Spam tokens have no balance worth checking, and posted collateral is
+    // out of the wallets.
+    for (asset_id, acq_vec) in inventory.0.iter().filter(|(asset_id, _)| !inventory::is_held_apart(asset_id)) {
+        let tot_inv: f64 = acq_vec.iter().map(|acq| acq.qty).sum();
+
+        let mut exp_bal = 0.0;
+        for name in names(asset_id) {
+            match balances.get(&name) {
+                Some(balance) => exp_bal += balance,
+                None => return Err(format!("no balance for {} (counted towards {})", name, asset_id).into()),
+            }
+        }
+
+        let surplus = tot_inv - exp_bal;
+        println!("{}, {}", asset_id, surplus);
+
+        // Position liquidity runs to many digits, so its float error is
+        // relative to its size.
+        let concentrated = position_assets::PositionAsset::parse(asset_id).is_some_and(|position| position.is_concentrated_liquidity());
+        let tolerance = if concentrated {
+            BALANCE_TOLERANCE.max(exp_bal.abs() * positions::LIQUIDITY_TOLERANCE)
+        } else {
+            BALANCE_TOLERANCE
+        };
+        if surplus > tolerance {
+            return Err(format!("{}: tot_inv: {}, exp_bal: {}", asset_id, tot_inv, exp_bal).into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use serde::{Serialize, Deserialize};
+
+
+/// Protocols whose positions are named as synthetic assets, one name per
+/// position or market.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionProtocol {
+    UniswapV3,
+    UniswapV4,
+    AerodromeSlipstream,
+    PancakeV3,
+    PendlePt,
+    PendleYt,
+}
+
+/// A position asset name, parsed. Concentrated-liquidity positions are
+/// `{prefix}:{tokenId}_{token0}_{token1}_{feeOrPoolId}_{tickLower}_{tickUpper}`;
+/// Pendle principal and yield tokens are `{prefix}:{market}_{underlying}_{expiry}`,
+/// with the market as token id, the underlying as `token0`, the expiry as
+/// `pool` and no ticks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PositionAsset {
+    pub protocol: PositionProtocol,
+    pub token_id: String,
+    pub token0: String,
+    pub token1: Option<String>,
+    /// Fee tier (V3-style pools), pool id (V4) or expiry (Pendle).
+    pub pool: String,
+    pub tick_lower: Option<i64>,
+    pub tick_upper: Option<i64>,
+}
+
+/// How one protocol names its position assets. A new protocol plugs in
+/// with an entry in `REGISTRY`.
+pub struct PositionFormat {
+    pub prefix: &'static str,
+    pub protocol: PositionProtocol,
+    pub parse: fn(PositionProtocol, &str) -> Option<PositionAsset>,
+    /// Units are interchangeable and priced like any token, so the asset
+    /// keeps its plain ticker and goes through the normal cost and
+    /// revenue paths instead of position handling.
+    pub fungible: bool,
+}
+
+pub const REGISTRY: &[PositionFormat] = &[
+    PositionFormat { prefix: "UNI-V3-LIQUIDITY:", protocol: PositionProtocol::UniswapV3, parse: parse_concentrated, fungible: false },
+    PositionFormat { prefix: "UNI-V4-LIQUIDITY:", protocol: PositionProtocol::UniswapV4, parse: parse_concentrated, fungible: false },
+    PositionFormat { prefix: "AERO-CL-LIQUIDITY:", protocol: PositionProtocol::AerodromeSlipstream, parse: parse_concentrated, fungible: false },
+    PositionFormat { prefix: "PANCAKE-V3-LIQUIDITY:", protocol: PositionProtocol::PancakeV3, parse: parse_concentrated, fungible: false },
+    PositionFormat { prefix: "PENDLE-PT:", protocol: PositionProtocol::PendlePt, parse: parse_pendle, fungible: true },
+    PositionFormat { prefix: "PENDLE-YT:", protocol: PositionProtocol::PendleYt, parse: parse_pendle, fungible: true },
+];
+
+impl PositionAsset {
+
+    /// Parses `asset` with the registered format its prefix matches.
+    pub fn parse(asset: &str) -> Option<Self> {
+        REGISTRY.iter()
+            .find(|format| asset.starts_with(format.prefix))
+            .and_then(|format| (format.parse)(format.protocol, &asset[format.prefix.len()..]))
+    }
+
+    /// `{token0}-{token1}`, or just the underlying when there is one token.
+    pub fn pair_name(&self) -> String {
+        match &self.token1 {
+            Some(token1) => format!("{}-{}", self.token0, token1),
+            None => self.token0.clone(),
+        }
+    }
+
+    /// Concentrated liquidity, as opposed to a tokenized yield position.
+    pub fn is_concentrated_liquidity(&self) -> bool {
+        self.tick_lower.is_some()
+    }
+}
+
+/// Whether `asset` names a non-fungible position of a registered protocol,
+/// so that it needs position handling throughout linking, cost basis and
+/// revenue. Fungible formats such as Pendle PT/YT are ordinary tokens here.
+pub fn is_position(asset: &str) -> bool {
+    REGISTRY.iter().any(|format| !format.fungible && asset.starts_with(format.prefix))
+}
+
+fn parse_concentrated(protocol: PositionProtocol, rest: &str) -> Option<PositionAsset> {
+    let fields: Vec<&str> = rest.split('_').collect();
+    if fields.len() != 6 {
+        return None
+    }
+    Some(PositionAsset {
+        protocol,
+        token_id: fields[0].to_string(),
+        token0: fields[1].to_string(),
+        token1: Some(fields[2].to_string()),
+        pool: fields[3].to_string(),
+        tick_lower: Some(fields[4].parse().ok()?),
+        tick_upper: Some(fields[5].parse().ok()?),
+    })
+}
+
+fn parse_pendle(protocol: PositionProtocol, rest: &str) -> Option<PositionAsset> {
+    let fields: Vec<&str> = rest.split('_').collect();
+    if fields.len() != 3 {
+        return None
+    }
+    Some(PositionAsset {
+        protocol,
+        token_id: fields[0].to_string(),
+        token0: fields[1].to_string(),
+        token1: None,
+        pool: fields[2].to_string(),
+        tick_lower: None,
+        tick_upper: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_concentrated_liquidity() {
+        let asset = PositionAsset::parse("UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200").unwrap();
+        assert_eq!(asset.protocol, PositionProtocol::UniswapV3);
+        assert_eq!(asset.token_id, "123");
+        assert_eq!(asset.pair_name(), "WETH-USDC");
+        assert_eq!(asset.pool, "500");
+        assert_eq!((asset.tick_lower, asset.tick_upper), (Some(-200), Some(200)));
+        assert!(asset.is_concentrated_liquidity());
+
+        for prefix in ["UNI-V4-LIQUIDITY:", "AERO-CL-LIQUIDITY:", "PANCAKE-V3-LIQUIDITY:"] {
+            let asset = PositionAsset::parse(&format!("{}7_A_B_0xpool_1_2", prefix)).unwrap();
+            assert!(asset.is_concentrated_liquidity());
+            assert!(is_position(&format!("{}7_A_B_0xpool_1_2", prefix)));
+        }
+    }
+
+    #[test]
+    fn parses_pendle() {
+        let asset = PositionAsset::parse("PENDLE-PT:0xmarket_stETH_2025-06-26").unwrap();
+        assert_eq!(asset.protocol, PositionProtocol::PendlePt);
+        assert_eq!(asset.token_id, "0xmarket");
+        assert_eq!(asset.pair_name(), "stETH");
+        assert_eq!(asset.pool, "2025-06-26");
+        assert!(!asset.is_concentrated_liquidity());
+
+        let asset = PositionAsset::parse("PENDLE-YT:0xmarket_stETH_2025-06-26").unwrap();
+        assert_eq!(asset.protocol, PositionProtocol::PendleYt);
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        assert!(PositionAsset::parse("UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200").is_none());
+        assert!(PositionAsset::parse("UNI-V3-LIQUIDITY:123_WETH_USDC_500_low_200").is_none());
+        assert!(PositionAsset::parse("PENDLE-PT:0xmarket_stETH").is_none());
+        assert!(PositionAsset::parse("WETH").is_none());
+    }
+
+    #[test]
+    fn pendle_tokens_are_fungible() {
+        assert!(!is_position("PENDLE-PT:0xmarket_stETH_2025-06-26"));
+        assert!(!is_position("PENDLE-YT:0xmarket_stETH_2025-06-26"));
+        assert!(is_position("UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200"));
+    }
+}
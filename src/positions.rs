@@ -0,0 +1,140 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use crate::deltas;
+use crate::position_assets;
+use chrono::{TimeZone, Utc};
+
+
+/// Liquidity left after a removal, relative to what was held, below which
+/// the removal closed the position. Only float error is left at that size.
+pub const LIQUIDITY_TOLERANCE: f64 = 0.000000001;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PositionStatus {
+    Open,
+    PartiallyClosed,
+    Closed,
+}
+
+/// One concentrated-liquidity position, by token id, as the year's
+/// add- and remove-liquidity deltas leave it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Position {
+    pub token_id: String,
+    /// `{token0}-{token1}`.
+    pub pair: String,
+    pub host: deltas::Host,
+    pub status: PositionStatus,
+    /// Liquidity held, in the position's own units.
+    pub liquidity: f64,
+    /// Unix millis of the last add or removal.
+    pub updated: u64,
+}
+
+impl Position {
+
+    /// A position first seen in `delta`, holding `liquidity`. Names that
+    /// don't parse keep the whole name as their id.
+    fn new(delta: &deltas::Delta, liquidity: f64) -> Self {
+        let (token_id, pair) = match position_assets::PositionAsset::parse(&delta.asset) {
+            Some(asset) => (asset.token_id.clone(), asset.pair_name()),
+            None => (delta.asset.clone(), String::new()),
+        };
+        Self {
+            token_id,
+            pair,
+            host: delta.host.clone(),
+            status: PositionStatus::Open,
+            liquidity,
+            updated: delta.timestamp,
+        }
+    }
+}
+
+/// The year's concentrated-liquidity positions, by inventory key.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Positions ( pub HashMap<String, Position> );
+
+impl Positions {
+
+    /// Liquidity added to the position under `key`, opening it if new.
+    pub fn add(&mut self, key: &str, delta: &deltas::Delta, held: f64) {
+        let position = self.0.entry(key.to_string()).or_insert_with(|| Position::new(delta, 0.0));
+        position.status = PositionStatus::Open;
+        position.liquidity = held + delta.qty;
+        position.updated = delta.timestamp;
+    }
+
+    /// Liquidity removed from the position under `key`, of which `held`
+    /// was held before. Returns whether the removal closed the position,
+    /// in which case all of `held` goes with it.
+    pub fn remove(&mut self, key: &str, delta: &deltas::Delta, held: f64) -> bool {
+        let closes = held - delta.qty <= held.abs() * LIQUIDITY_TOLERANCE;
+        let position = self.0.entry(key.to_string()).or_insert_with(|| Position::new(delta, held));
+        if closes {
+            position.status = PositionStatus::Closed;
+            position.liquidity = 0.0;
+        } else {
+            position.status = PositionStatus::PartiallyClosed;
+            position.liquidity = held - delta.qty;
+        }
+        position.updated = delta.timestamp;
+        closes
+    }
+
+    pub fn report(&self) -> String {
+        let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().format("%F").to_string();
+
+        let mut positions: Vec<(&String, &Position)> = self.0.iter().collect();
+        positions.sort_by_key(|(key, _)| key.as_str());
+
+        let mut report = "token id, pair, host, status, liquidity, last change:\n".to_string();
+        for (_, p) in positions {
+            report += &format!(" {} {} {:?} {:?} {} {}\n", p.token_id, p.pair, p.host, p.status, p.liquidity, date(p.updated));
+        }
+        report
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    const NAME: &str = "UNI-V3-LIQUIDITY:123_WETH_USDC_500_-200_200";
+
+    fn liquidity(timestamp: u64, direction: deltas::Direction, qty: f64) -> deltas::Delta {
+        fixtures::delta(timestamp, direction, deltas::Ilk::ManageLiquidity, NAME, qty)
+    }
+
+    #[test]
+    fn add_opens_a_position_by_token_id() {
+        let mut positions = Positions::default();
+        positions.add(NAME, &liquidity(1, deltas::Direction::In, 100.0), 0.0);
+        positions.add(NAME, &liquidity(2, deltas::Direction::In, 50.0), 100.0);
+        let position = &positions.0[NAME];
+        assert_eq!((position.token_id.as_str(), position.pair.as_str()), ("123", "WETH-USDC"));
+        assert_eq!((position.status, position.liquidity, position.updated), (PositionStatus::Open, 150.0, 2));
+    }
+
+    #[test]
+    fn partial_removal_leaves_the_rest() {
+        let mut positions = Positions::default();
+        positions.add(NAME, &liquidity(1, deltas::Direction::In, 100.0), 0.0);
+        assert!(!positions.remove(NAME, &liquidity(2, deltas::Direction::Out, 40.0), 100.0));
+        let position = &positions.0[NAME];
+        assert_eq!((position.status, position.liquidity), (PositionStatus::PartiallyClosed, 60.0));
+    }
+
+    #[test]
+    fn removal_within_tolerance_closes() {
+        let mut positions = Positions::default();
+        positions.add(NAME, &liquidity(1, deltas::Direction::In, 100.0), 0.0);
+        assert!(positions.remove(NAME, &liquidity(2, deltas::Direction::Out, 100.0 - 1e-9), 100.0));
+        let position = &positions.0[NAME];
+        assert_eq!((position.status, position.liquidity), (PositionStatus::Closed, 0.0));
+
+        assert!(!Positions::default().remove(NAME, &liquidity(3, deltas::Direction::Out, 99.9), 100.0));
+    }
+}
@@ -2,6 +2,7 @@ use std::error::Error;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use chrono::{TimeZone, Utc, Timelike, DateTime};
+use crate::error;
 
 
 
@@ -247,20 +248,16 @@ impl Prices {
 
     // }
 
-    pub fn price_at_millis(&self, asset: &str, timestamp: u64) -> f64 {
-        let datetime = Utc.timestamp_millis(timestamp as i64);
+    pub fn price_at_millis(&self, asset: &str, timestamp: u64) -> Result<f64, error::CalcError> {
+        let datetime = Utc.timestamp_millis_opt(timestamp as i64).unwrap();
 
         let p = {
              // let floor = datetime.date().and_hms(0, 0, 0).to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-             let date: String = datetime.date().format("%F").to_string();;
-             if !self.map.contains_key(asset) {
-                 dbg!(asset);
-             } else if !self.map[asset].contains_key(&date) {
-                 dbg!(asset);
-                 dbg!(&date);
+             let date: String = datetime.date_naive().format("%F").to_string();
+             match self.map.get(asset).and_then(|day_prices| day_prices.get(&date)) {
+                 Some(price) => *price,
+                 None => return Err(error::CalcError::MissingPrice { asset: asset.to_string(), date }),
              }
-             self.map[asset][&date]
-
         };
         // let p = match self.granularity {
         //     Granularity::D1 => {
@@ -283,7 +280,7 @@ impl Prices {
         //     }
     
         // };
-        p
+        Ok(p)
     }
 
     // pub fn price_at_millis(&self, asset: &str, timestamp: u64) -> f64 {
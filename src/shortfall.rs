@@ -0,0 +1,126 @@
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use crate::deltas;
+use crate::inventory;
+use chrono::{TimeZone, Utc};
+
+
+/// What `Inventory::apply_deltas` does when a disposal takes more than the
+/// lots held under its key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ShortfallPolicy {
+    /// Each shortfall is an error at the delta that comes up short; the
+    /// run carries on to report every one, then fails.
+    Error,
+    /// The missing quantity has zero basis and is short term.
+    #[default]
+    ZeroBasis,
+    /// The missing quantity takes the basis of the next acquisitions under
+    /// the same key, and is short term. Lots transferred in count as
+    /// acquisitions, and a short transfer waits for the sending wallet's.
+    /// Whatever no later acquisition covers by the end of the year falls
+    /// back to zero basis.
+    LaterAcquisition,
+}
+
+/// A disposal or wallet transfer of more than was held: a gap in the data
+/// rather than something that happened on chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Shortfall {
+    /// Tax ticker of the asset short.
+    pub asset: String,
+    pub host: deltas::Host,
+    pub account: String,
+    pub identifier: String,
+    pub ilk: deltas::Ilk,
+    pub timestamp: u64,
+    pub qty: f64,
+    /// Parts of later acquisitions whose basis covered the shortfall, under
+    /// `ShortfallPolicy::LaterAcquisition`.
+    pub resolved_by: Vec<inventory::Lot>,
+}
+
+impl Shortfall {
+
+    pub fn new(asset: &str, delta: &deltas::Delta, qty: f64) -> Self {
+        Self {
+            asset: asset.to_string(),
+            host: delta.host.clone(),
+            account: delta.account.clone(),
+            identifier: delta.identifier.clone(),
+            ilk: delta.ilk.clone(),
+            timestamp: delta.timestamp,
+            qty,
+            resolved_by: Vec::new(),
+        }
+    }
+
+    /// Quantity no later acquisition covered, which has zero basis.
+    pub fn zero_basis_qty(&self) -> f64 {
+        self.qty - self.resolved_by.iter().map(|lot| lot.qty).sum::<f64>()
+    }
+}
+
+/// Quantity a disposal took under `ShortfallPolicy::LaterAcquisition`
+/// that no acquisition covered by the end of its year. That year reports it
+/// at zero basis; the next year's first acquisitions under the same key
+/// make the quantity good before they become lots, and the basis they give
+/// up is reported that year as a row with no proceeds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Owed {
+    /// Tax ticker.
+    pub asset: String,
+    /// The disposal that came up short.
+    pub delta: deltas::Delta,
+    pub qty: f64,
+}
+
+impl Owed {
+
+    pub fn load_all(path: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Vec<Self> = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
+    pub fn save_all(owed: &[Self], path: &str) -> Result<(), Box<dyn Error>> {
+        let json_string = serde_json::to_string(owed)?;
+        std::fs::write(path, &json_string)?;
+        Ok(())
+    }
+}
+
+/// The shortfalls as a CSV, one row per resolving acquisition plus one
+/// for any zero-basis remainder.
+pub fn report(shortfalls: &[Shortfall]) -> String {
+    let date = |millis: u64| Utc.timestamp_millis_opt(millis as i64).unwrap().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+    let mut csv = "asset,host,account,identifier,ilk,date,shortfall_quantity,resolution,resolving_identifier,resolving_date,quantity,cost_basis\n".to_string();
+    for shortfall in shortfalls {
+        let prefix = format!(
+            "{},{:?},{},{},{:?},{},{:.8}",
+            shortfall.asset,
+            shortfall.host,
+            shortfall.account,
+            shortfall.identifier,
+            shortfall.ilk,
+            date(shortfall.timestamp),
+            shortfall.qty,
+        );
+        for lot in &shortfall.resolved_by {
+            csv += &format!(
+                "{},later_acquisition,{},{},{:.8},{:.8}\n",
+                prefix,
+                lot.identifier.clone().unwrap_or_default(),
+                date(lot.timestamp),
+                lot.qty,
+                lot.cost,
+            );
+        }
+        let remainder = shortfall.zero_basis_qty();
+        if remainder > crate::specific_id::QTY_TOLERANCE {
+            csv += &format!("{},zero_basis,,,{:.8},0\n", prefix, remainder);
+        }
+    }
+    csv
+}
@@ -0,0 +1,139 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::error::Error;
+use crate::deltas;
+use crate::inventory;
+
+/// Slack allowed when checking that selected quantities add up, to absorb
+/// float error from earlier partial-lot splits.
+pub const QTY_TOLERANCE: f64 = 0.000000001;
+
+
+/// A specific identification selection file: which acquisition lots each
+/// disposal or loan repayment consumes. Those without an entry, and
+/// wallet transfers, use `default_method`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LotSelections {
+    pub default_method: inventory::InventoryMethod,
+    pub selections: Vec<LotSelection>,
+}
+
+/// The lots consumed by one disposal, keyed by the disposing delta's
+/// identifier and on-chain asset. `ilk` is only needed to tell apart
+/// disposals of the same asset in one transaction (a swap and its gas);
+/// when several entries share a key they are used in order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LotSelection {
+    pub identifier: String,
+    pub asset: String,
+    #[serde(default)]
+    pub ilk: Option<deltas::Ilk>,
+    pub lots: Vec<SelectedLot>,
+}
+
+impl LotSelection {
+
+    /// Records the lots a disposal actually consumed.
+    pub fn from_lots(delta: &deltas::Delta, lots: &[inventory::Lot]) -> Self {
+        Self {
+            identifier: delta.identifier.clone(),
+            asset: delta.asset.clone(),
+            ilk: Some(delta.ilk.clone()),
+            lots: lots.iter().map(|lot| SelectedLot {
+                identifier: lot.identifier.clone(),
+                timestamp: lot.timestamp,
+                qty: lot.qty,
+            }).collect(),
+        }
+    }
+
+    fn matches(&self, delta: &deltas::Delta) -> bool {
+        self.identifier == delta.identifier
+            && self.asset == delta.asset
+            && self.ilk.as_ref().is_none_or(|ilk| *ilk == delta.ilk)
+    }
+}
+
+/// A held lot, matched on its `Lot.identifier` and `Lot.timestamp`, and
+/// the quantity to take from it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SelectedLot {
+    pub identifier: Option<String>,
+    pub timestamp: u64,
+    pub qty: f64,
+}
+
+impl LotSelections {
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let inner: Self = serde_json::from_str(&data)?;
+        Ok(inner)
+    }
+
+    pub fn save (&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let json_string = serde_json::to_string_pretty(&self)?;
+        std::fs::write(path, &json_string)?;
+        Ok(())
+    }
+
+    /// The first selection for `delta` whose index isn't in `used`.
+    pub fn find_unused(&self, delta: &deltas::Delta, used: &HashSet<usize>) -> Option<(usize, &LotSelection)> {
+        self.selections.iter()
+            .enumerate()
+            .find(|(i, s)| !used.contains(i) && s.matches(delta))
+    }
+
+    /// The selections whose index isn't in `used`, in file order.
+    pub fn unused(&self, used: &HashSet<usize>) -> Vec<&LotSelection> {
+        self.selections.iter()
+            .enumerate()
+            .filter(|(i, _)| !used.contains(i))
+            .map(|(_, s)| s)
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn selection(identifier: &str, ilk: Option<deltas::Ilk>) -> LotSelection {
+        LotSelection { identifier: identifier.to_string(), asset: "ETH".to_string(), ilk, lots: Vec::new() }
+    }
+
+    #[test]
+    fn matches_on_identifier_asset_and_ilk() {
+        let swap = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Swap, "ETH", 1.0);
+        let mut gas = swap.clone();
+        gas.ilk = deltas::Ilk::SwapGas;
+        let mut other_asset = swap.clone();
+        other_asset.asset = "WETH".to_string();
+
+        assert!(selection(&swap.identifier, None).matches(&swap));
+        assert!(selection(&swap.identifier, None).matches(&gas));
+        assert!(selection(&swap.identifier, Some(deltas::Ilk::Swap)).matches(&swap));
+        assert!(!selection(&swap.identifier, Some(deltas::Ilk::Swap)).matches(&gas));
+        assert!(!selection(&swap.identifier, None).matches(&other_asset));
+        assert!(!selection("0xdef", None).matches(&swap));
+    }
+
+    #[test]
+    fn entries_sharing_a_key_are_used_in_order() {
+        let swap = fixtures::delta(fixtures::noon(fixtures::DAY), deltas::Direction::Out, deltas::Ilk::Swap, "ETH", 1.0);
+        let selections = LotSelections {
+            default_method: inventory::InventoryMethod::Fifo,
+            selections: vec![selection("0xdef", None), selection(&swap.identifier, None), selection(&swap.identifier, None)],
+        };
+        let mut used = HashSet::new();
+        let (first, _) = selections.find_unused(&swap, &used).unwrap();
+        assert_eq!(first, 1);
+        used.insert(first);
+        assert_eq!(selections.find_unused(&swap, &used).unwrap().0, 2);
+        used.insert(2);
+        assert!(selections.find_unused(&swap, &used).is_none());
+        assert_eq!(selections.unused(&used).len(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::deltas;
+use crate::position_assets;
 
 
 
@@ -7,10 +8,16 @@ use crate::deltas;
 
 
 pub fn delta_tax_ticker (delta: &deltas::Delta) -> String {
-    if deltas::is_uni_cl_position(&delta.asset) {
-        format!("{}:{}", delta.asset, delta.host.to_string())
+    tax_ticker(&delta.asset, &delta.host)
+}
+
+/// Tax ticker for `asset` held on `host`. Position assets
+/// are distinct per host, everything else maps through its on-chain ticker.
+pub fn tax_ticker (asset: &str, host: &deltas::Host) -> String {
+    if position_assets::is_position(asset) {
+        format!("{}:{}", asset, host.to_string())
     } else {
-        onchain_ticker_to_tax_ticker(&delta.asset)
+        onchain_ticker_to_tax_ticker(asset)
     }
 }
 
@@ -29,8 +36,6 @@ pub fn onchain_ticker_to_tax_ticker (onchain_ticker: &str) -> String {
         String::from("USDC")
     } else if onchain_ticker == "WSOL" {
         String::from("SOL")
-    } else if deltas::is_uni_cl_position(onchain_ticker) {
-        panic!();
     } else {
         String::from(onchain_ticker)
     };
@@ -1,6 +0,0 @@
-pub mod twenty;
-pub mod twenty_one;
-pub mod twenty_two;
-pub mod twenty_three;
-pub mod twenty_four;
-pub mod twenty_five;